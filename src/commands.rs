@@ -1,41 +1,58 @@
-use futures::future;
+use std::collections::BTreeMap;
 use std::option::Option;
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde::{Serialize, Deserialize};
 use tokio::fs;
-use crate::utility::{self, verbose_log_async};
+use crate::interaction::command_definitions;
+use crate::rest::{RestError, RestResult, REST};
+use crate::utility::{self, is_false};
 
 #[derive(Debug, Deserialize)]
-struct ErrorResponse {
-    code: u16,
-    message: String,
-    errors: Option<ErrorDetails>,
+pub struct ErrorResponse {
+    pub code: u32,
+    pub message: String,
+    pub errors: Option<ErrorDetails>,
 }
 
 #[derive(Debug, Deserialize)]
-struct ErrorDetails {
+pub struct ErrorDetails {
     #[serde(flatten)]
     _fields: serde_json::Value,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Command {
+pub const COMMAND_CHAT_INPUT: u8 = 1;
+pub const COMMAND_MESSAGE: u8 = 3;
+
+pub const OPTION_SUB_COMMAND: u8 = 1;
+pub const OPTION_STRING: u8 = 3;
+pub const OPTION_INTEGER: u8 = 4;
+pub const OPTION_BOOLEAN: u8 = 5;
+pub const OPTION_NUMBER: u8 = 10;
+pub const OPTION_ATTACHMENT: u8 = 11;
+
+const COMMANDS_FILE: &str = "commands.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Command {
     name: String,
     #[serde(rename = "type")]
     r#type: u8,
+    #[serde(default)]
     description: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<Vec<CommandOption>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CommandOption {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandOption {
     name: String,
     description: String,
     #[serde(rename = "type")]
     r#type: u8,
+    #[serde(default, skip_serializing_if = "is_false")]
     required: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Vec<CommandOption>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     choices: Option<Vec<CommandChoice>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     min_value: Option<i32>,
@@ -49,110 +66,291 @@ struct CommandOption {
     autocomplete: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct CommandChoice {
     name: String,
     value: String,
 }
 
-async fn read_commands_from_file(file_path: &str) -> Result<Vec<Command>, ()> {
-    let file_content = fs::read_to_string(file_path).await.map_err(|_| ())?;
-    match serde_json::from_str(&file_content) {
-        Ok(commands) => Ok(commands),
-        Err(e) => {
-            let error_message = format!("Error reading commands.json file: {}", e.to_string());
-            verbose_log_async(&error_message).await;
+impl Command {
+    fn new(r#type: u8, name: &str, description: &str) -> Self {
+        Self { name: name.to_string(), r#type, description: description.to_string(), options: None }
+    }
 
-            Err(())
+    pub fn slash(name: &str, description: &str) -> Self {
+        Self::new(COMMAND_CHAT_INPUT, name, description)
+    }
+
+    pub fn message(name: &str) -> Self {
+        Self::new(COMMAND_MESSAGE, name, "")
+    }
+
+    pub fn option(mut self, option: CommandOption) -> Self {
+        self.options.get_or_insert_with(Vec::new).push(option);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Options are looked up in the invoked subcommand, or at the top level for commands without one.
+    pub fn declares_option(&self, subcommand: Option<&str>, name: &str) -> bool {
+        let options = match subcommand {
+            Some(subcommand) => self.options
+                .iter()
+                .flatten()
+                .find(|option| option.r#type == OPTION_SUB_COMMAND && option.name == subcommand)
+                .and_then(|option| option.options.as_ref()),
+            None => self.options.as_ref(),
+        };
+
+        options.into_iter().flatten().any(|option| option.r#type != OPTION_SUB_COMMAND && option.name == name)
+    }
+}
+
+impl CommandOption {
+    fn new(r#type: u8, name: &str, description: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            r#type,
+            required: false,
+            options: None,
+            choices: None,
+            min_value: None,
+            max_value: None,
+            min_length: None,
+            max_length: None,
+            autocomplete: None,
         }
     }
+
+    pub fn subcommand(name: &str, description: &str) -> Self {
+        Self::new(OPTION_SUB_COMMAND, name, description)
+    }
+
+    pub fn string(name: &str, description: &str) -> Self {
+        Self::new(OPTION_STRING, name, description)
+    }
+
+    pub fn integer(name: &str, description: &str) -> Self {
+        Self::new(OPTION_INTEGER, name, description)
+    }
+
+    pub fn boolean(name: &str, description: &str) -> Self {
+        Self::new(OPTION_BOOLEAN, name, description)
+    }
+
+    pub fn number(name: &str, description: &str) -> Self {
+        Self::new(OPTION_NUMBER, name, description)
+    }
+
+    pub fn attachment(name: &str, description: &str) -> Self {
+        Self::new(OPTION_ATTACHMENT, name, description)
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn option(mut self, option: CommandOption) -> Self {
+        self.options.get_or_insert_with(Vec::new).push(option);
+        self
+    }
+
+    pub fn choice(mut self, name: &str, value: &str) -> Self {
+        self.choices.get_or_insert_with(Vec::new).push(CommandChoice { name: name.to_string(), value: value.to_string() });
+        self
+    }
+
+    pub fn range(mut self, min_value: i32, max_value: i32) -> Self {
+        self.min_value = Some(min_value);
+        self.max_value = Some(max_value);
+        self
+    }
+
+    pub fn max_length(mut self, max_length: i32) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    pub fn autocomplete(mut self) -> Self {
+        self.autocomplete = Some(true);
+        self
+    }
 }
 
-async fn register_command(
-    target_url: String,
-    client: reqwest::Client,
-    command_json: String,
-    headers: HeaderMap,
-) -> Result<(), ()> {
-    let result = client
-        .post(&target_url)
-        .headers(headers)
-        .body(command_json.clone())
-        .send()
-        .await;
-
-    verbose_log_async(format!("Sending request to {} with body {}", target_url, command_json).as_str()).await;
-
-    match result {
-        Ok(result) => {
-            let body = result.text().await.unwrap();
-            verbose_log_async(format!("Response body: {}", body).as_str()).await;
-
-            if let Ok(parsed_error) = serde_json::from_str::<ErrorResponse>(&body) {
-                let log_message = format!(
-                    "Register command error {}: {}\n{:?}\n",
-                    parsed_error.code,
-                    parsed_error.message,
-                    parsed_error.errors
-                );
-
-                verbose_log_async(&log_message).await;
-                return Err(());
-            }
+fn commands_payload(commands: &[Command]) -> String {
+    let mut payload = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut payload, serde_json::ser::PrettyFormatter::with_indent(b"    "));
+    commands.serialize(&mut serializer).unwrap();
 
-            Ok(())
-        },
+    format!("{}\n", String::from_utf8(payload).unwrap())
+}
 
+pub async fn write_commands_file() -> Result<(), ()> {
+    let commands = command_definitions();
+
+    match fs::write(COMMANDS_FILE, commands_payload(&commands)).await {
+        Ok(_) => {
+            println!("Wrote {} commands to {}", commands.len(), COMMANDS_FILE);
+            Ok(())
+        }
         Err(e) => {
-            let log_message = format!(
-                "Error sending request to {} with body {}: {}\n",
-                target_url,
-                command_json,
-                e.to_string()
-            );
-
-            verbose_log_async(&log_message).await;
+            println!("Failed to write {}: {}", COMMANDS_FILE, e);
             Err(())
         }
     }
 }
 
-pub async fn register_commands() -> Result<(), ()> {
+async fn fetch_registered_commands() -> RestResult<Vec<Command>> {
     let config = &utility::CONFIG;
-    let target_url = format!("{}/applications/{}/commands", config.base_api_url, config.app_id);
-
-    match read_commands_from_file("commands.json").await {
-        Ok(commands) => {
-            let client = reqwest::Client::new();
-            let mut headers = HeaderMap::new();
-
-            headers.insert(CONTENT_TYPE, HeaderValue::from_str(&config.content_type).unwrap());
-            headers.insert("Authorization", HeaderValue::from_str(&config.auth).unwrap());
-            headers.insert("User-Agent", HeaderValue::from_str(&config.user_agent).unwrap());
-
-            let futures: Vec<_> = commands.iter().map(|command| {
-                let target_url = target_url.clone();
-                let client = client.clone();
-                let command_json = serde_json::to_string(command).unwrap();
-                let headers = headers.clone();
-                register_command(target_url, client, command_json, headers)
-            }).collect();
-
-            let results = future::join_all(futures).await;
-
-            for i in 0..commands.len() {
-                match results[i] {
-                    Ok(_) => println!("Loaded command: {}", commands[i].name),
-                    Err(_) => println!("Failed to load command: {}", commands[i].name),
-                }
+
+    REST.get_commands(&config.app_id, config.dev_guild_id.as_deref()).await
+}
+
+async fn overwrite_commands(commands: &[Command]) -> Result<Vec<Command>, ()> {
+    let config = &utility::CONFIG;
+
+    REST.bulk_overwrite_commands(&config.app_id, config.dev_guild_id.as_deref(), commands).await.map_err(|e| {
+        println!("Failed to overwrite commands: {}", e);
+    })
+}
+
+fn normalize_options(options: &mut Option<Vec<CommandOption>>) {
+    if options.as_ref().is_some_and(|options| options.is_empty()) {
+        *options = None;
+    }
+
+    for option in options.iter_mut().flatten() {
+        if option.autocomplete == Some(false) {
+            option.autocomplete = None;
+        }
+        normalize_options(&mut option.options);
+    }
+}
+
+fn normalize(commands: &[Command]) -> BTreeMap<String, serde_json::Value> {
+    commands
+        .iter()
+        .map(|command| {
+            let mut command = command.clone();
+            normalize_options(&mut command.options);
+            (command.name.clone(), serde_json::to_value(&command).unwrap())
+        })
+        .collect()
+}
+
+fn diff_commands(local: &[Command], remote: &[Command]) -> Vec<String> {
+    let local = normalize(local);
+    let remote = normalize(remote);
+    let mut diff = Vec::new();
+
+    for (name, definition) in &local {
+        match remote.get(name) {
+            None => diff.push(format!("+ {}", name)),
+            Some(registered) if registered != definition => {
+                diff.push(format!("~ {}", name));
+                diff.push(format!("    registered: {}", registered));
+                diff.push(format!("    local:      {}", definition));
             }
+            Some(_) => {}
+        }
+    }
 
-            Ok(())
+    for name in remote.keys() {
+        if !local.contains_key(name) {
+            diff.push(format!("- {}", name));
         }
+    }
+
+    diff
+}
 
-        Err(_) => {
-            println!("Error reading commands from file");
+// Registered commands this version cannot read, such as integer choices, are overwritten like any other change.
+fn registered_changes(local: &[Command], registered: RestResult<Vec<Command>>) -> Result<Vec<String>, ()> {
+    match registered {
+        Ok(registered) => Ok(diff_commands(local, &registered)),
+        Err(RestError::Decode(e)) => {
+            println!("Failed to decode registered commands, overwriting them: {}", e);
+            Ok(local.iter().map(|command| format!("~ {}", command.name)).collect())
+        }
+        Err(e) => {
+            println!("Failed to fetch registered commands: {}", e);
             Err(())
         }
     }
 }
+
+pub async fn sync_commands(dry_run: bool) -> Result<(), ()> {
+    let commands = command_definitions();
+
+    let diff = registered_changes(&commands, fetch_registered_commands().await)?;
+
+    let target = match utility::CONFIG.dev_guild_id.as_ref() {
+        Some(guild_id) => format!("guild {}", guild_id),
+        None => "global".to_string(),
+    };
+
+    if diff.is_empty() {
+        println!("Commands are up to date ({})", target);
+        return Ok(());
+    }
+
+    println!("Command changes ({}):", target);
+    for line in &diff {
+        println!("{}", line);
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let synced = overwrite_commands(&commands).await?;
+    for command in synced {
+        println!("Loaded command: {}", command.name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_file_matches_declarations() {
+        let declared = command_definitions();
+        let file: Vec<Command> = serde_json::from_str(include_str!("../commands.json")).unwrap();
+
+        assert!(diff_commands(&declared, &file).is_empty(), "commands.json is out of date, run with --write-commands");
+        assert_eq!(include_str!("../commands.json"), commands_payload(&declared));
+    }
+
+    #[test]
+    fn unreadable_registered_commands_are_overwritten() {
+        let local = vec![Command::slash("ping", "Ping the bot")];
+        let registered = r#"[{"name":"roll","type":1,"description":"Roll","options":[{"name":"sides","type":4,"description":"Sides","choices":[{"name":"six","value":6}]}]}]"#;
+        let decoded = serde_json::from_str::<Vec<Command>>(registered).map_err(RestError::Decode);
+
+        assert_eq!(registered_changes(&local, decoded), Ok(vec!["~ ping".to_string()]));
+        assert_eq!(registered_changes(&local, Err(RestError::Status(500))), Err(()));
+    }
+
+    #[test]
+    fn options_are_declared_per_subcommand() {
+        let command = Command::slash("game", "Game")
+            .option(CommandOption::subcommand("start", "Start").option(CommandOption::boolean("confirm", "Confirm")))
+            .option(CommandOption::subcommand("stop", "Stop"));
+
+        assert!(command.declares_option(Some("start"), "confirm"));
+        assert!(!command.declares_option(Some("stop"), "confirm"));
+        assert!(!command.declares_option(None, "confirm"));
+        assert!(!command.declares_option(None, "start"));
+
+        let word = Command::slash("word", "Word").option(CommandOption::string("word", "Word"));
+        assert!(word.declares_option(None, "word"));
+    }
+}
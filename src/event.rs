@@ -1,97 +1,101 @@
-use std::{collections::HashMap, future::Future, pin::Pin};
+use std::{collections::{HashMap, HashSet}, future::Future, pin::Pin};
 use regex::Regex;
-use reqwest::Client;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
 use lazy_static::lazy_static;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::fs;
+use futures::future::join_all;
+use tokio::sync::{Mutex, RwLock};
 
-use crate::{game::{channel_exists, contains_word, find_levenstein_distance, find_piece_equals, register, save_channel, CHANNELS}, utility::{generate_basic_message, generate_client, get_word_valid, verbose_log_async, CONFIG}};
+use crate::{game::{add_word, channel_exists, channel_settings, clear_challenged, contains_word, find_levenstein_distance, find_piece_equals, register, register_thread, remove_word, turn_error}, model::{Message, PartialChannel, ReactionEvent, PUBLIC_THREAD}, message::{AllowedMentions, Embed, MessageBuilder}, rest::REST, utility::{get_word_valid, is_false, verbose_log_async, CONFIG, HTTP_CLIENT, IDENTITY}};
 
 lazy_static! {
-    static ref VOTES: Arc<RwLock<HashMap<String, String>>> = Arc::new(RwLock::new(HashMap::new()));
+    static ref VOTES: Arc<RwLock<HashMap<String, PendingVote>>> = Arc::new(RwLock::new(HashMap::new()));
+    static ref CONTENT_WARNED: Arc<RwLock<HashSet<String>>> = Arc::new(RwLock::new(HashSet::new()));
+    // Thread parent of every channel seen on the gateway or looked up once, None for channels that are not threads.
+    static ref THREAD_PARENTS: Arc<RwLock<HashMap<String, Option<String>>>> = Arc::new(RwLock::new(HashMap::new()));
 }
 
-const VALID_VOTE: &str = "👍";
-const INVALID_VOTE: &str = "👎";
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    #[serde(rename = "type")]
-    r#type: u8,
-    id: String,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PendingVote {
     channel_id: String,
-    content: String,
-    mentions: Vec<User>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    reactions: Option<Vec<Reaction>>,
+    word: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    challenge: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Reaction {
-    count: u8,
-    count_details: CountDetails,
-    me: bool,
-    me_burst: bool,
-    emoji: Emoji
-}
+const VOTES_FILE: &str = "votes.json";
+const VALID_VOTE: &str = "👍";
+const INVALID_VOTE: &str = "👎";
+const UNKNOWN_MESSAGE: u32 = 10008;
+const THREAD_AUTO_ARCHIVE_MINUTES: u32 = 1440;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CountDetails {
-    burst: u8,
-    normal: u8
+pub async fn remember_channels<'a>(channels: impl IntoIterator<Item = &'a PartialChannel>) {
+    let mut parents = THREAD_PARENTS.write().await;
+    for channel in channels {
+        parents.insert(channel.id.clone(), channel.thread_parent());
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct User {
-    id: String,
-    username: String,
-    global_name: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    bot: Option<bool>,
-    permission_type: i32,
+pub async fn forget_channel(channel_id: &str) {
+    THREAD_PARENTS.write().await.remove(channel_id);
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct UpdateReaction {
-    emoji: Emoji,
-    channel_id: String,
-    message_id: String
-}
+async fn thread_parent_of(channel_id: &str) -> Option<String> {
+    if let Some(parent_id) = THREAD_PARENTS.read().await.get(channel_id) {
+        return parent_id.clone();
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Emoji {
-    name: String,
+    match REST.get_channel(channel_id).await {
+        Ok(channel) => {
+            remember_channels([&channel]).await;
+            channel.thread_parent()
+        }
+        Err(e) => {
+            verbose_log_async(format!("Failed to get channel {}: {}", channel_id, e).as_str()).await;
+            None
+        }
+    }
 }
 
-pub async fn check_mention_for_me(event: &serde_json::Value) -> Result<(), ()> {
+pub async fn check_mention_for_me(message: &Message) -> Result<(), ()> {
     let mut through_flag = true;
     let mut send_flag = false;
-    let mentions = event["d"]["mentions"].clone();
-    let channel_id = event["d"]["channel_id"].as_str().unwrap();
-    let mut message = "Internal Error".to_string();
-
-    if mentions.is_array() && mentions.as_array().unwrap().iter()
-        .any(|mention| mention["username"].as_str().unwrap()== "NS Shiritori") {
-            if channel_exists(channel_id).await == false {
-                if register(channel_id.to_string()).await.is_ok() {
-                    message = "チャンネルの登録が完了しました。".to_string();
+    let channel_id = message.channel_id.as_str();
+    let mut response = "Internal Error".to_string();
+
+    let bot_user_id = match IDENTITY.read().await.as_ref() {
+        Some(identity) => identity.user_id.clone(),
+        None => return Err(()),
+    };
+
+    if message.mentions.iter().any(|mention| mention.id == bot_user_id) {
+            if !channel_exists(channel_id).await {
+                // Mentions inside a thread register the thread itself, so it is archived when its game ends.
+                let registered = match thread_parent_of(channel_id).await {
+                    Some(parent_id) => register_thread(channel_id.to_string(), parent_id, message.guild_id.clone()).await,
+                    None => register(channel_id.to_string(), message.guild_id.clone()).await,
+                };
+
+                if registered.is_ok() {
+                    response = "チャンネルの登録が完了しました。".to_string();
                 } else {
-                    message = "登録が失敗しました。".to_string();
+                    response = "登録が失敗しました。".to_string();
                 }
             } else {
-                message = "このチャンネルは既に登録されています。".to_string();
+                response = "このチャンネルは既に登録されています。".to_string();
             }
             send_flag = true;
             through_flag = false;
     }
 
-    let client = generate_client();
-
     if send_flag {
-        let send_message = generate_basic_message(message.as_str());
-        client.post(&format!("{}/channels/{}/messages", CONFIG.base_api_url, channel_id)).body(send_message).send().await.unwrap();
+        if let Err(e) = REST.create_message(channel_id, &MessageBuilder::new().content(response).reply_to(&message.id)).await {
+            verbose_log_async(format!("Failed to send message: {}", e).as_str()).await;
+        }
     }
 
     if through_flag {
@@ -101,248 +105,286 @@ pub async fn check_mention_for_me(event: &serde_json::Value) -> Result<(), ()> {
     }
 }
 
-pub async fn check_word(word: String, channel_id: String) {
-    let reg = Regex::new(r"^[a-zA-Z][a-zA-Z\s\-]*[a-zA-Z]$").unwrap();
+pub async fn warn_missing_content(channel_id: String, message_id: String) {
+    {
+        let mut warned = CONTENT_WARNED.write().await;
+        if !warned.insert(channel_id.clone()) { return; }
+    }
 
-    if reg.is_match(&word) {
-        verbose_log_async(format!("Valid word: {}", word).as_str()).await;
-        let space_reg = Regex::new(r"\s+").unwrap();
-        let mut replaced = word.replace("-", " ");
-        replaced = replaced.to_lowercase();
-        replaced = space_reg.replace_all(&replaced, " ").to_string();
+    println!("Received a message without content in {}, the MESSAGE_CONTENT intent is probably not granted", channel_id);
 
-        manage_exsist_word(channel_id.clone(), replaced.clone()).await;
-        manage_find_word(channel_id.clone(), replaced.clone()).await;
-        manage_find_weblio_word(channel_id.clone(), replaced.clone()).await;
-        manage_like_word(channel_id.clone(), replaced.clone()).await;
-        manage_valid_vote(channel_id.clone(), replaced.clone()).await;
+    let send_message = MessageBuilder::new()
+        .content("メッセージの内容を読み取れません。/word コマンドで単語を送信してください。")
+        .reply_to(&message_id);
+    if let Err(e) = REST.create_message(&channel_id, &send_message).await {
+        verbose_log_async(format!("Failed to send message: {}", e).as_str()).await;
     }
 }
 
-async fn manage_exsist_word(channel_id: String, word: String) {
-    let gen_after = {
-        let channel_id = channel_id.clone();
-        let word = word.clone();
-        move |_message: Message| {
-            Box::pin(async move {
-                let contains = contains_word(channel_id.clone(), word.clone()).await;
-                let next_message;
-                if contains {
-                    next_message = format!("{} は既に使用されています。", word);
-                } else {
-                    next_message = format!("{} と完全一致する単語は使用されていません。", word);
-                }
-
-                generate_basic_message(next_message.as_str())
-            }) as Pin<Box<dyn Future<Output = String> + Send>>
+pub async fn start_thread_game(channel_id: &str, guild_id: Option<String>) -> Result<String, ()> {
+    let body = serde_json::json!({ "name": "しりとり", "type": PUBLIC_THREAD, "auto_archive_duration": THREAD_AUTO_ARCHIVE_MINUTES });
+    let thread = match REST.start_thread(channel_id, &body).await {
+        Ok(thread) => thread,
+        Err(e) => {
+            verbose_log_async(format!("Failed to start a thread in {}: {}", channel_id, e).as_str()).await;
+            return Err(());
         }
     };
 
-    send_and_patch(channel_id, format!("{} を検索中...", word), gen_after).await;
+    if register_thread(thread.id.clone(), channel_id.to_string(), guild_id).await.is_err() {
+        return Err(());
+    }
+
+    let welcome = MessageBuilder::new().content("しりとりを開始しました。このスレッドに単語を送信してください。");
+    if let Err(e) = REST.create_message(&thread.id, &welcome).await {
+        verbose_log_async(format!("Failed to send message: {}", e).as_str()).await;
+    }
+
+    Ok(thread.id)
 }
 
+pub async fn archive_thread(thread_id: &str) {
+    if let Err(e) = REST.modify_channel(thread_id, &serde_json::json!({ "archived": true })).await {
+        verbose_log_async(format!("Failed to archive thread {}: {}", thread_id, e).as_str()).await;
+    }
+}
 
+pub fn normalize_word(word: &str) -> Option<String> {
+    let reg = Regex::new(r"^[a-zA-Z][a-zA-Z\s\-]*[a-zA-Z]$").unwrap();
 
-async fn manage_find_word(channel_id: String, word: String) {
-    let gen_after = {
-        let word = word.clone();
-        move |_message: Message| {
-            Box::pin(async move {
-                let exists = get_word_valid(word.clone()).await;
-                let next_message;
-                if exists {
-                    next_message = format!("{} が dictionary api で見つかりました。", word);
-                } else {
-                    next_message = format!("{} は dictionary apiでは見つかりませんでした。", word);
-                }
+    if !reg.is_match(word) {
+        return None;
+    }
+
+    let space_reg = Regex::new(r"\s+").unwrap();
+    let mut replaced = word.replace("-", " ");
+    replaced = replaced.to_lowercase();
+    replaced = space_reg.replace_all(&replaced, " ").to_string();
 
-                generate_basic_message(next_message.as_str())
-            }) as Pin<Box<dyn Future<Output = String> + Send>>
+    Some(replaced)
+}
+
+pub fn parse_word_list(text: &str) -> (Vec<String>, usize) {
+    let mut words = Vec::new();
+    let mut invalid = 0;
+
+    for entry in text.split(['\n', ',', '、']).map(str::trim).filter(|entry| !entry.is_empty()) {
+        match normalize_word(entry) {
+            Some(word) => words.push(word),
+            None => invalid += 1,
         }
-    };
+    }
 
-    send_and_patch(channel_id, format!("{} を dictionary api で検索中...", word), gen_after).await;
+    (words, invalid)
 }
 
-async fn manage_find_weblio_word(channel_id: String, word: String) {
-    let gen_after = {
-        let word = word.clone();
-        move |_message: Message| {
-            Box::pin(async move {
-                match reqwest::get(format!("https://ejje.weblio.jp/content/{}", word)).await {
-                    Ok(res) => {
-                        if res.status().is_success() {
-                            let body = res.text().await.unwrap();
-                            let document = Html::parse_document(&body);
-                            let selector = Selector::parse(".content-explanation").unwrap();
-
-                            let mut meanings = String::from("");
-                            for element in document.select(&selector) {
-                                meanings += element.inner_html().trim();
-                            }
-
-                            return generate_basic_message(format!("weblio で {} が見つかりました。\\n - 意味: {}", word, meanings).as_str());
-                        } else {
-                            return generate_basic_message(format!("weblio で {} は見つかりませんでした。", word).as_str());
-                        }
-                    },
-                    Err(_) => generate_basic_message("Internal Error.")
-                }
-            }) as Pin<Box<dyn Future<Output = String> + Send>>
+struct WordReport {
+    word: String,
+    checks: Vec<(&'static str, Option<String>)>,
+}
+
+impl WordReport {
+    fn embed(&self) -> Embed {
+        let mut embed = Embed::new().title(format!("「{}」", self.word));
+        for (name, result) in &self.checks {
+            embed = embed.field(*name, result.as_deref().unwrap_or("確認中..."), false);
         }
-    };
 
-    send_and_patch(channel_id, format!("{} を意味を weblio で検索中...", word), gen_after).await;
+        embed.footer(format!("{} / {} で有効投票を行います。", VALID_VOTE, INVALID_VOTE))
+    }
 }
 
-async fn manage_like_word(channel_id: String, word: String) {
-    let gen_after = {
-        let channel_id = channel_id.clone();
-        let word = word.clone();
-        move |_message: Message| {
-            Box::pin(async move {
-                let (piece, distance): (Option<Vec<String>>, Option<Vec<String>>) = tokio::join!(
-                    find_piece_equals(channel_id.clone(), word.clone()),
-                    find_levenstein_distance(channel_id.clone(), word.clone(), CONFIG.msg_dist_threshold)
-                );
-
-                let mut result = Vec::<String>::new();
-                if let Some(piece) = piece {
-                    result = piece;
-                }
-                if let Some(dist) = distance {
-                    result.extend(dist);
-                }
+type Check = Pin<Box<dyn Future<Output = String> + Send>>;
 
-                result = result
-                    .iter()
-                    .map(|s| format!(" - {}", s))
-                    .collect();
+// `reply_to` is the player's message, slash command words have none since the interaction response already shows who sent them.
+pub async fn check_word(word: String, channel_id: String, user_id: Option<String>, reply_to: Option<String>) {
+    let replaced = match normalize_word(&word) {
+        Some(replaced) => replaced,
+        None => return,
+    };
+    verbose_log_async(format!("Valid word: {}", word).as_str()).await;
 
-                let next_message;
-                if result.is_empty() {
-                    next_message = format!("{} に近似する単語は使用されていません。", word);
-                } else {
-                    let joined_result = result.join("\\n");
-                    next_message = format!("{} に近い単語\\n{}\\nが見つかりました。", word, joined_result);
-                }
+    let out_of_turn = match user_id.as_deref() {
+        Some(user_id) => turn_error(&channel_id, user_id).await,
+        None => None,
+    };
+    if let Some(error) = out_of_turn {
+        if let Err(e) = REST.send_content(&channel_id, &error, reply_to.as_deref()).await {
+            verbose_log_async(format!("Failed to send message: {}", e).as_str()).await;
+        }
+        return;
+    }
+
+    let settings = channel_settings(&channel_id).await;
+
+    let mut checks: Vec<(&'static str, Check)> = vec![("使用済みの単語", Box::pin(check_used(channel_id.clone(), replaced.clone())))];
+    if settings.dictionary_check {
+        checks.push(("dictionary api", Box::pin(check_dictionary(replaced.clone()))));
+    }
+    if settings.weblio_check {
+        checks.push(("weblio", Box::pin(check_weblio(replaced.clone()))));
+    }
+    checks.push(("近い単語", Box::pin(check_similar(channel_id.clone(), replaced.clone(), settings.similarity_threshold))));
 
-                generate_basic_message(next_message.as_str())
-            }) as Pin<Box<dyn Future<Output = String> + Send>>
+    let report = WordReport { word: replaced.clone(), checks: checks.iter().map(|(name, _)| (*name, None)).collect() };
+    let mut report_message = MessageBuilder::new().embed(report.embed());
+    if let Some(message_id) = reply_to.as_deref() {
+        report_message = report_message.reply_to(message_id);
+    }
+    let message = match REST.create_message(&channel_id, &report_message).await {
+        Ok(message) => message,
+        Err(e) => {
+            verbose_log_async(format!("Failed to send word report: {}", e).as_str()).await;
+            return;
         }
     };
 
-    send_and_patch(channel_id, format!("{} を使用単語から検索中...", word), gen_after).await;
+    open_vote(&channel_id, &message.id, PendingVote { channel_id: channel_id.clone(), word: replaced, user_id, challenge: false }).await;
+
+    // Each check fills in its own field as soon as it finishes, the lock keeps the edits in order.
+    let report = Arc::new(Mutex::new(report));
+    let updates = checks.into_iter().enumerate().map(|(index, (_, check))| {
+        let report = report.clone();
+        let channel_id = channel_id.clone();
+        let message_id = message.id.clone();
+
+        async move {
+            let result = check.await;
+            let mut report = report.lock().await;
+            report.checks[index].1 = Some(result);
+
+            if let Err(e) = REST.edit_message(&channel_id, &message_id, &MessageBuilder::new().embed(report.embed())).await {
+                verbose_log_async(format!("Failed to update word report: {}", e).as_str()).await;
+            }
+        }
+    });
+    join_all(updates).await;
 }
 
-async fn manage_valid_vote(channel_id: String, word: String) {
-    let client = generate_client();
-    let message = generate_basic_message(format!("「{}」 の有効投票を開始します。", word).as_str());
+async fn check_used(channel_id: String, word: String) -> String {
+    if contains_word(channel_id, word.clone()).await {
+        format!("{} は既に使用されています。", word)
+    } else {
+        format!("{} と完全一致する単語は使用されていません。", word)
+    }
+}
 
-    let res = match client.post(format!("{}/channels/{}/messages", CONFIG.base_api_url, channel_id)).body(message).send().await {
-        Ok(res) => res,
-        Err(_) => return,
-    };
+async fn check_dictionary(word: String) -> String {
+    if get_word_valid(word.clone()).await {
+        format!("{} が dictionary api で見つかりました。", word)
+    } else {
+        format!("{} は dictionary apiでは見つかりませんでした。", word)
+    }
+}
+
+async fn check_weblio(word: String) -> String {
+    match HTTP_CLIENT.get(format!("{}{}", CONFIG.weblio_url, word)).send().await {
+        Ok(res) => {
+            if res.status().is_success() {
+                let body = res.text().await.unwrap_or_default();
+                let document = Html::parse_document(&body);
+                let selector = Selector::parse(".content-explanation").unwrap();
+
+                let mut meanings = String::from("");
+                for element in document.select(&selector) {
+                    meanings += element.inner_html().trim();
+                }
+
+                format!("weblio で {} が見つかりました。\n - 意味: {}", word, meanings)
+            } else {
+                format!("weblio で {} は見つかりませんでした。", word)
+            }
+        },
+        Err(_) => "Internal Error.".to_string()
+    }
+}
 
-    let text = res.text().await.unwrap_or("".to_string());
+async fn check_similar(channel_id: String, word: String, threshold: f64) -> String {
+    let (piece, distance): (Option<Vec<String>>, Option<Vec<String>>) = tokio::join!(
+        find_piece_equals(channel_id.clone(), word.clone()),
+        find_levenstein_distance(channel_id.clone(), word.clone(), threshold)
+    );
 
-    let json: Message = match serde_json::from_str(text.as_str()) {
+    let mut result = Vec::<String>::new();
+    if let Some(piece) = piece {
+        result = piece;
+    }
+    if let Some(dist) = distance {
+        result.extend(dist);
+    }
+
+    result = result
+        .iter()
+        .map(|s| format!(" - {}", s))
+        .collect();
+
+    if result.is_empty() {
+        format!("{} に近似する単語は使用されていません。", word)
+    } else {
+        let joined_result = result.join("\n");
+        format!("{} に近い単語\n{}\nが見つかりました。", word, joined_result)
+    }
+}
+
+pub async fn challenge_word(channel_id: String, word: String, user_id: Option<String>, message_id: String, message_link: String) {
+    // Only the author of the challenged word is pinged.
+    let (content, mentions) = match user_id.as_deref() {
+        Some(author_id) => (
+            format!("<@{}> {} の「{}」 に異議が申し立てられました。有効投票を開始します。", author_id, message_link, word),
+            AllowedMentions::default().user(author_id),
+        ),
+        None => (format!("{} の「{}」 に異議が申し立てられました。有効投票を開始します。", message_link, word), AllowedMentions::default()),
+    };
+    let message = MessageBuilder::new().content(content).reply_to(&message_id).allowed_mentions(mentions);
+    let json = match REST.create_message(&channel_id, &message).await {
         Ok(json) => json,
-        Err(_) => {
-            verbose_log_async("Failed to parse message at vote").await;
+        Err(e) => {
+            verbose_log_async(format!("Failed to send vote message: {}", e).as_str()).await;
+            let _ = clear_challenged(channel_id, word).await;
             return
         }
     };
-    let msg_id = json.id.clone();
-    
+
+    open_vote(&channel_id, &json.id, PendingVote { channel_id: channel_id.clone(), word, user_id, challenge: true }).await;
+}
+
+async fn open_vote(channel_id: &str, message_id: &str, vote: PendingVote) {
     {
         let mut vote_lock = VOTES.write().await;
-        vote_lock.insert(channel_id.clone(), msg_id);
+        vote_lock.insert(message_id.to_string(), vote);
     }
 
-    // up %F0%9F%91%8D%EF%B8%8F
-    // down %F0%9F%91%8E%EF%B8%8F
-    send_vote(client.clone(), channel_id.clone(), json.id.clone(), VALID_VOTE.to_string()).await;
-    send_vote(client, channel_id, json.id.clone(), INVALID_VOTE.to_string()).await;
-}
-
-async fn send_vote(client: Client, channel_id: String, message_id: String, vote: String) {
-    let _ = client.put(format!(r#"{}/channels/{}/messages/{}/reactions/{}/@me"#, CONFIG.base_api_url, channel_id, message_id, vote)).send().await;
+    send_vote(channel_id, message_id, VALID_VOTE).await;
+    send_vote(channel_id, message_id, INVALID_VOTE).await;
 }
 
-async fn send_and_patch<F>(channel_id: String, first_message: String, gen_second_message: F) where F: FnOnce(Message) -> Pin<Box<dyn Future<Output = String> + Send>> + Send + 'static, {
-    let client = generate_client();
-    let first_message_raw = generate_basic_message(first_message.as_str());
-    let res = match client.post(format!("{}/channels/{}/messages", CONFIG.base_api_url, channel_id)).body(first_message_raw).send().await {
-        Ok(res) => res,
-        Err(_) => return,
-    };
-    
-    tokio::spawn(async move {
-        let json: Message = match res.json().await {
-            Ok(json) => json,
-            Err(_) => {
-                verbose_log_async("Failed to parse message").await;
-                return
-            }
-        };
-    
-        let message_id = json.id.clone();
-    
-        let second_message = gen_second_message(json).await;
-
-        println!("second message: {}", second_message);
-    
-        match client.patch(format!("{}/channels/{}/messages/{}", CONFIG.base_api_url, channel_id, message_id)).body(second_message).send().await {
-            Ok(res) => verbose_log_async(format!("Message edit: {}", res.status()).as_str()).await,
-            Err(e) => verbose_log_async(format!("Failed to send message: {}", e).as_str()).await,
-        };
-    });
+async fn send_vote(channel_id: &str, message_id: &str, vote: &str) {
+    if let Err(e) = REST.create_reaction(channel_id, message_id, vote).await {
+        verbose_log_async(format!("Failed to add vote reaction: {}", e).as_str()).await;
+    }
 }
 
-pub async fn update_vote(d: &serde_json::Value) {
-    let data: UpdateReaction = match serde_json::from_value(d.clone()) {
-        Ok(data) => data,
-        Err(_) => {
-            verbose_log_async(format!("Failed to parse message: {}", d).as_str()).await;
+pub async fn update_vote(data: &ReactionEvent) {
+    let target_reaction = match data.emoji.name.as_deref() {
+        Some(name) if name == VALID_VOTE || name == INVALID_VOTE => name,
+        _ => {
+            verbose_log_async("Reaction emoji is not vote emoji").await;
             return;
         }
     };
 
-    if !(data.emoji.name == VALID_VOTE || data.emoji.name == INVALID_VOTE) { 
-        verbose_log_async("Reaction emoji is not vote emoji").await;
-        return;
-    }
-
-    let target_reaction = data.emoji.name;
-
-    let message;
-    {
-        let votes = VOTES.write().await;
-
-        if let Some(vote_message_id) = votes.get(&data.channel_id) {
-            if vote_message_id !=  data.message_id.as_str() { return; }
-        }
+    if !VOTES.read().await.contains_key(&data.message_id) { return; }
 
-        let client = generate_client();
-        match client.get(format!("{}/channels/{}/messages/{}", CONFIG.base_api_url, data.channel_id, data.message_id)).send().await {
-            Ok(res) => {
-                let text = res.text().await.unwrap_or("".to_string());
-                message = match serde_json::from_str::<Message>(text.as_str()) {
-                    Ok(message) => message,
-                    Err(err) => {
-                        verbose_log_async("Failed to parse message").await;
-                        verbose_log_async(err.to_string().as_str()).await;
-                        return;
-                    }
-                };
-            },
-            Err(_) => {
-                verbose_log_async("Failed to get message").await;
-                return;
+    let message = match REST.get_message(&data.channel_id, &data.message_id).await {
+        Ok(message) => message,
+        Err(e) => {
+            verbose_log_async(format!("Failed to get vote message: {}", e).as_str()).await;
+            if e.code() == Some(UNKNOWN_MESSAGE) {
+                discard_vote(&data.message_id).await;
             }
-        };
-    }
+            return;
+        }
+    };
 
     let reactions = match message.reactions {
         Some(reactions) => reactions,
@@ -352,7 +394,7 @@ pub async fn update_vote(d: &serde_json::Value) {
         }
     };
 
-    let target_reaction = reactions.iter().find(|x| x.emoji.name == target_reaction);
+    let target_reaction = reactions.iter().find(|x| x.emoji.name.as_deref() == Some(target_reaction));
 
     let match_reaction = match target_reaction {
         Some(reaction) => reaction,
@@ -362,45 +404,106 @@ pub async fn update_vote(d: &serde_json::Value) {
         }
     };
 
-    if match_reaction.count >= CONFIG.vote_count {
+    // The bot seeds both vote reactions itself, so its own reaction is not a vote.
+    let vote_count = match_reaction.count - u8::from(match_reaction.me);
+
+    if vote_count >= channel_settings(&data.channel_id).await.vote_count {
         verbose_log_async("Vote count is over").await;
 
-        {
-            let mut votes = VOTES.write().await;
-            votes.remove(data.message_id.as_str());
+        let vote = match take_vote(&data.message_id).await {
+            Some(vote) => vote,
+            None => return,
+        };
+
+        if let Err(e) = REST.delete_all_reactions(&data.channel_id, &data.message_id).await {
+            verbose_log_async(format!("Failed to clear vote reactions: {}", e).as_str()).await;
         }
 
-        let client = generate_client();
-        let _ = client.delete(format!("{}/channels/{}/messages/{}/reactions", CONFIG.base_api_url, data.channel_id, data.message_id)).send().await;
+        let is_valid = match_reaction.emoji.name.as_deref() == Some(VALID_VOTE);
+        let new_message = match (is_valid, vote.challenge) {
+            (true, true) => "可決されました。この単語は引き続き使用済みです。",
+            (true, false) => "可決されました。この単語を使用リストに追加します。",
+            (false, true) => "否決されました。この単語を使用リストから削除します。",
+            (false, false) => "否決されました。",
+        };
 
-        let new_message;
-        if match_reaction.emoji.name == VALID_VOTE {
-            new_message = "可決されました。この単語を使用リストに追加します。";
-        } else {
-            new_message = "否決されました。";
+        match REST.edit_message(&data.channel_id, &data.message_id, &MessageBuilder::new().content(new_message)).await {
+            Ok(_) => verbose_log_async("Vote message edited").await,
+            Err(e) => verbose_log_async(format!("Failed to edit vote message: {}", e).as_str()).await,
         }
 
-        let new_raw_message = generate_basic_message(new_message);
+        // The word can be challenged again once this vote is decided.
+        if vote.challenge {
+            let _ = clear_challenged(vote.channel_id.clone(), vote.word.clone()).await;
+        }
 
-        match client.patch(format!("{}/channels/{}/messages/{}", CONFIG.base_api_url, data.channel_id, data.message_id)).body(new_raw_message).send().await {
-            Ok(res) => verbose_log_async(format!("Message edit: {}", res.status()).as_str()).await,
-            Err(e) => verbose_log_async(format!("Failed to send message: {}", e).as_str()).await,
+        // A challenged word is already used, so upholding it leaves the word list and the turn as they are.
+        if is_valid && !vote.challenge {
+            if add_word(vote.channel_id, vote.word.clone(), vote.user_id).await.is_err() {
+                verbose_log_async(format!("Failed to add {} to the used words", vote.word).as_str()).await;
+            }
+        } else if !is_valid && vote.challenge && remove_word(vote.channel_id, vote.word.clone()).await.is_err() {
+            verbose_log_async(format!("Failed to remove {} from the used words", vote.word).as_str()).await;
         }
+    }
+}
 
-        let word_regex = regex::Regex::new(r"^「([a-zA-Z][a-zA-Z\s\-]*[a-zA-Z])」.*$").unwrap();
-        let word = match word_regex.captures(message.content.as_str()) {
-            Some(captures) => captures.get(1).unwrap().as_str(),
-            None => return
-        };
+pub async fn save_votes() {
+    let votes = VOTES.read().await;
+    let votes_data = serde_json::to_string(&*votes).unwrap();
 
-        if match_reaction.emoji.name == VALID_VOTE {
-            {
-                let mut channels = CHANNELS.write().await;
-                let channel = channels.get_mut(&data.channel_id).unwrap();
-                channel.words.as_mut().unwrap().insert(word.to_string());
-            }
+    match fs::write(VOTES_FILE, votes_data).await {
+        Ok(_) => verbose_log_async(format!("Saved {} pending votes", votes.len()).as_str()).await,
+        Err(e) => println!("Failed to save pending votes: {}", e),
+    }
+}
 
-            save_channel(data.channel_id).await;
+pub async fn load_votes() {
+    let content = match fs::read_to_string(VOTES_FILE).await {
+        Ok(content) => content,
+        Err(_) => return,
+    };
+
+    match serde_json::from_str::<HashMap<String, PendingVote>>(&content) {
+        Ok(saved) => {
+            let mut votes = VOTES.write().await;
+            votes.extend(saved);
+            verbose_log_async(format!("Loaded {} pending votes", votes.len()).as_str()).await;
+        }
+        Err(e) => println!("Failed to parse {}: {}", VOTES_FILE, e),
+    }
+}
+
+pub async fn discard_channel_votes(channel_id: &str) {
+    let discarded: HashMap<String, PendingVote> = {
+        let mut votes = VOTES.write().await;
+        let (discarded, kept): (HashMap<_, _>, HashMap<_, _>) = votes.drain().partition(|(_, vote)| vote.channel_id == channel_id);
+        *votes = kept;
+        discarded
+    };
+
+    // A vote message left behind could no longer decide anything.
+    for (message_id, vote) in discarded {
+        if let Err(e) = REST.delete_message(&vote.channel_id, &message_id).await {
+            verbose_log_async(format!("Failed to delete vote message: {}", e).as_str()).await;
         }
+        release_challenge(vote).await;
     }
-}
\ No newline at end of file
+}
+
+async fn release_challenge(vote: PendingVote) {
+    if vote.challenge {
+        let _ = clear_challenged(vote.channel_id, vote.word).await;
+    }
+}
+
+async fn take_vote(message_id: &str) -> Option<PendingVote> {
+    let mut votes = VOTES.write().await;
+    votes.remove(message_id)
+}
+
+pub async fn discard_vote(message_id: &str) {
+    if let Some(vote) = take_vote(message_id).await {
+        release_challenge(vote).await;
+    }
+}
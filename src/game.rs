@@ -1,7 +1,7 @@
 use std::cmp::max;
 use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::fs::{create_dir_all, metadata, File};
+use tokio::fs::{create_dir_all, metadata, remove_dir_all, rename, File};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::RwLock;
 use lazy_static::lazy_static;
@@ -9,7 +9,7 @@ use edit_distance::edit_distance;
 
 use serde::{Serialize, Deserialize};
 
-use crate::utility::verbose_log_async;
+use crate::utility::{verbose_log_async, CONFIG};
 
 lazy_static! {
     pub static ref CHANNELS: Arc<RwLock<HashMap<String, Channel>>> = Arc::new(RwLock::new(HashMap::new()));
@@ -19,16 +19,95 @@ lazy_static! {
 pub struct Channel {
     pub channel_id: String,
     pub users: VecDeque<String>,
-    pub words: Option<BTreeSet<String>>
+    pub words: Option<BTreeSet<String>>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<String>,
+    #[serde(default)]
+    pub history: Vec<WordEntry>,
+    #[serde(default)]
+    pub settings: ChannelSettings,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub challenged: BTreeSet<String>,
+    // Set when the game runs in a thread, the thread is archived when the game ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
 }
 
-pub async fn register(original_id: String) -> Result<(), i32> {
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ChannelSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similarity_threshold: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vote_count: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary_check: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weblio_check: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub similarity_threshold: f64,
+    pub vote_count: u8,
+    pub dictionary_check: bool,
+    pub weblio_check: bool,
+}
+
+impl ChannelSettings {
+    pub fn resolve(&self) -> Settings {
+        Settings {
+            similarity_threshold: self.similarity_threshold.unwrap_or(CONFIG.msg_dist_threshold),
+            vote_count: self.vote_count.unwrap_or(CONFIG.vote_count),
+            dictionary_check: self.dictionary_check.unwrap_or(true),
+            weblio_check: self.weblio_check.unwrap_or(true),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WordEntry {
+    pub word: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Removal {
+    Archive,
+    Delete,
+}
+
+impl Removal {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "archive" => Some(Removal::Archive),
+            "delete" => Some(Removal::Delete),
+            _ => None,
+        }
+    }
+}
+
+fn default_active() -> bool {
+    true
+}
+
+pub async fn register(original_id: String, guild_id: Option<String>) -> Result<(), i32> {
+    create_channel(original_id, guild_id, None).await
+}
+
+pub async fn register_thread(thread_id: String, parent_id: String, guild_id: Option<String>) -> Result<(), i32> {
+    create_channel(thread_id, guild_id, Some(parent_id)).await
+}
+
+async fn create_channel(original_id: String, guild_id: Option<String>, parent_id: Option<String>) -> Result<(), i32> {
     verbose_log_async(format!("Registering channel {}", original_id).as_str()).await;
 
     let channel_path = format!("channels/{}", original_id);
     let path_name = format!("{}/data.json", &channel_path);
 
-    if let Err(_) = create_dir_all(&channel_path).await {
+    if create_dir_all(&channel_path).await.is_err() {
         verbose_log_async(format!("Failed to create directory: {}", channel_path).as_str()).await;
         return Err(1);
     }
@@ -36,7 +115,13 @@ pub async fn register(original_id: String) -> Result<(), i32> {
     let basic_channel = Channel {
         channel_id: original_id.clone(),
         users: VecDeque::new(),
-        words: BTreeSet::new().into()
+        words: BTreeSet::new().into(),
+        active: true,
+        guild_id,
+        history: Vec::new(),
+        settings: ChannelSettings::default(),
+        challenged: BTreeSet::new(),
+        parent_id,
     };
 
     let file_result = File::create_new(&path_name).await;
@@ -100,7 +185,10 @@ pub async fn load_channel(channel_id: String) -> Result<(), i32> {
 
 pub async fn save_channel(_channel_id: String) {
     let channels = CHANNELS.read().await;
-    let channel = channels.get(&_channel_id).unwrap();
+    let channel = match channels.get(&_channel_id) {
+        Some(channel) => channel,
+        None => return,
+    };
 
     let channel_file_path = format!("channels/{}/data.json", _channel_id);
     let channel_data = serde_json::to_string(&channel).unwrap();
@@ -115,7 +203,7 @@ pub async fn save_channel(_channel_id: String) {
     file.write_all(channel_data.as_bytes()).await.unwrap();
 }
 
-pub async fn _save_all_channels() {
+pub async fn save_all_channels() {
     let mut channel_ids = Vec::<String>::new();
 
     {
@@ -125,11 +213,7 @@ pub async fn _save_all_channels() {
         }
     }
 
-    for channel_id in channel_ids {
-        tokio::spawn(async move {
-            save_channel(channel_id).await;
-        });
-    }
+    futures::future::join_all(channel_ids.into_iter().map(save_channel)).await;
 }
 
 pub async fn channel_exists(channel_id: &str) -> bool {
@@ -137,10 +221,237 @@ pub async fn channel_exists(channel_id: &str) -> bool {
     channels.contains_key(channel_id)
 }
 
+pub async fn channel_active(channel_id: &str) -> bool {
+    let channels = CHANNELS.read().await;
+    channels.get(channel_id).is_some_and(|channel| channel.active)
+}
+
+pub async fn get_channel(channel_id: &str) -> Option<Channel> {
+    let channels = CHANNELS.read().await;
+    channels.get(channel_id).cloned()
+}
+
+async fn update_channel<T, F>(channel_id: String, update: F) -> Result<T, i32> where F: FnOnce(&mut Channel) -> T {
+    let result = {
+        let mut channels = CHANNELS.write().await;
+        let channel = match channels.get_mut(&channel_id) {
+            Some(channel) => channel,
+            None => return Err(1),
+        };
+        update(channel)
+    };
+
+    save_channel(channel_id).await;
+    Ok(result)
+}
+
+pub async fn unregister(channel_id: String, removal: Removal) -> Result<(), i32> {
+    verbose_log_async(format!("Unregistering channel {} ({:?})", channel_id, removal).as_str()).await;
+
+    // The lock is held over the move so no save can write the directory back, and a failed move keeps the channel.
+    let mut channels = CHANNELS.write().await;
+    if !channels.contains_key(&channel_id) {
+        return Err(1);
+    }
+
+    let channel_path = format!("channels/{}", channel_id);
+    let result = match removal {
+        Removal::Archive => {
+            let archive_path = format!("archive/{}-{}", channel_id, chrono::offset::Local::now().format("%Y%m%d%H%M%S"));
+            match create_dir_all("archive").await {
+                Ok(_) => rename(&channel_path, &archive_path).await,
+                Err(e) => Err(e),
+            }
+        }
+        Removal::Delete => remove_dir_all(&channel_path).await,
+    };
+
+    match result {
+        // A channel that was never saved has nothing to move.
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            verbose_log_async(format!("Failed to remove {}: {}", channel_path, e).as_str()).await;
+            Err(2)
+        }
+        _ => {
+            channels.remove(&channel_id);
+            Ok(())
+        }
+    }
+}
+
+pub async fn thread_parent(channel_id: &str) -> Option<String> {
+    let channels = CHANNELS.read().await;
+    channels.get(channel_id).and_then(|channel| channel.parent_id.clone())
+}
+
+pub async fn threads_in_channel(parent_id: &str) -> Vec<String> {
+    let channels = CHANNELS.read().await;
+    channels
+        .values()
+        .filter(|channel| channel.parent_id.as_deref() == Some(parent_id))
+        .map(|channel| channel.channel_id.clone())
+        .collect()
+}
+
+pub async fn channels_in_guild(guild_id: &str) -> Vec<String> {
+    let channels = CHANNELS.read().await;
+    channels
+        .values()
+        .filter(|channel| channel.guild_id.as_deref() == Some(guild_id))
+        .map(|channel| channel.channel_id.clone())
+        .collect()
+}
+
+pub async fn assign_guild(channel_ids: Vec<String>, guild_id: String) {
+    let mut updated = Vec::new();
+
+    {
+        let mut channels = CHANNELS.write().await;
+        for channel_id in channel_ids {
+            if let Some(channel) = channels.get_mut(&channel_id) {
+                if channel.guild_id.is_none() {
+                    channel.guild_id = Some(guild_id.clone());
+                    updated.push(channel_id);
+                }
+            }
+        }
+    }
+
+    for channel_id in updated {
+        save_channel(channel_id).await;
+    }
+}
+
+pub async fn set_active(channel_id: String, active: bool) -> Result<(), i32> {
+    update_channel(channel_id, |channel| channel.active = active).await
+}
+
+pub async fn add_user(channel_id: String, user_id: String) -> Result<bool, i32> {
+    update_channel(channel_id, |channel| {
+        if channel.users.contains(&user_id) {
+            return false;
+        }
+        channel.users.push_back(user_id);
+        true
+    }).await
+}
+
+pub async fn remove_user(channel_id: String, user_id: String) -> Result<bool, i32> {
+    update_channel(channel_id, |channel| {
+        let before = channel.users.len();
+        channel.users.retain(|user| *user != user_id);
+        channel.users.len() != before
+    }).await
+}
+
+pub async fn skip_turn(channel_id: String) -> Result<Option<String>, i32> {
+    update_channel(channel_id, |channel| {
+        channel.users.rotate_left(1.min(channel.users.len()));
+        channel.users.front().cloned()
+    }).await
+}
+
+pub async fn reset_channel(channel_id: String) -> Result<(), i32> {
+    update_channel(channel_id, |channel| {
+        channel.words = Some(BTreeSet::new());
+        channel.history.clear();
+        channel.challenged.clear();
+    }).await
+}
+
+pub async fn channel_settings(channel_id: &str) -> Settings {
+    let channels = CHANNELS.read().await;
+    match channels.get(channel_id) {
+        Some(channel) => channel.settings.resolve(),
+        None => ChannelSettings::default().resolve(),
+    }
+}
+
+pub async fn update_settings<F>(channel_id: String, update: F) -> Result<Settings, i32> where F: FnOnce(&mut ChannelSettings) {
+    update_channel(channel_id, |channel| {
+        update(&mut channel.settings);
+        channel.settings.resolve()
+    }).await
+}
+
+// The turn passes to the next player once the current player's word is accepted.
+pub async fn add_word(channel_id: String, word: String, user_id: Option<String>) -> Result<bool, i32> {
+    update_channel(channel_id, |channel| {
+        let inserted = channel.words.get_or_insert_with(BTreeSet::new).insert(word.clone());
+        if inserted {
+            if user_id.is_some() && channel.users.front() == user_id.as_ref() {
+                channel.users.rotate_left(1);
+            }
+            channel.history.push(WordEntry { word, user_id });
+        }
+        inserted
+    }).await
+}
+
+// Anyone can play until somebody joins, after that only the player whose turn it is.
+pub async fn turn_error(channel_id: &str, user_id: &str) -> Option<String> {
+    let channels = CHANNELS.read().await;
+    let users = &channels.get(channel_id)?.users;
+
+    match users.front() {
+        None => None,
+        Some(current) if current == user_id => None,
+        Some(_) if !users.iter().any(|user| user == user_id) => Some("ゲームに参加していません。/shiritori join で参加してください。".to_string()),
+        Some(current) => Some(format!("<@{}> の手番です。", current)),
+    }
+}
+
+pub async fn import_words(channel_id: String, words: Vec<String>) -> Result<usize, i32> {
+    update_channel(channel_id, |channel| {
+        let used = channel.words.get_or_insert_with(BTreeSet::new);
+        let mut added = 0;
+        for word in words {
+            if used.insert(word.clone()) {
+                channel.history.push(WordEntry { word, user_id: None });
+                added += 1;
+            }
+        }
+        added
+    }).await
+}
+
+pub async fn remove_word(channel_id: String, word: String) -> Result<bool, i32> {
+    update_channel(channel_id, |channel| {
+        let removed = channel.words.as_mut().is_some_and(|words| words.remove(&word));
+        channel.history.retain(|entry| entry.word != word);
+        removed
+    }).await
+}
+
+pub async fn mark_challenged(channel_id: String, word: String) -> Result<bool, i32> {
+    update_channel(channel_id, |channel| channel.challenged.insert(word)).await
+}
+
+pub async fn clear_challenged(channel_id: String, word: String) -> Result<bool, i32> {
+    update_channel(channel_id, |channel| channel.challenged.remove(&word)).await
+}
+
+pub async fn word_history(channel_id: &str) -> Option<Vec<WordEntry>> {
+    let channels = CHANNELS.read().await;
+    let channel = channels.get(channel_id)?;
+
+    // Words used before the history was recorded have no known order or player, so they come first.
+    let recorded: BTreeSet<&String> = channel.history.iter().map(|entry| &entry.word).collect();
+    let mut history: Vec<WordEntry> = channel.words.iter().flatten()
+        .filter(|word| !recorded.contains(word))
+        .map(|word| WordEntry { word: word.clone(), user_id: None })
+        .collect();
+    history.extend(channel.history.iter().cloned());
+
+    Some(history)
+}
+
 pub async fn contains_word(channel_id: String, word: String) -> bool {
     let channels = CHANNELS.read().await;
-    let channel = channels.get(&channel_id).unwrap();
-    channel.words.as_ref().unwrap().contains(&word)
+    channels
+        .get(&channel_id)
+        .and_then(|channel| channel.words.as_ref())
+        .is_some_and(|words| words.contains(&word))
 }
 
 pub async fn find_piece_equals(channel_id: String, word: String) -> Option<Vec<String>> {
@@ -186,4 +497,67 @@ pub async fn find_levenstein_distance(channel_id: String, word: String, threshol
     }
 }
 
-//queue.make_contiguous().reverse();
\ No newline at end of file
+pub async fn suggest_words(channel_id: String, partial: String, threshold: f64, limit: usize) -> Vec<String> {
+    if partial.is_empty() {
+        let channels = CHANNELS.read().await;
+        return match channels.get(&channel_id).and_then(|channel| channel.words.as_ref()) {
+            Some(words) => words.iter().take(limit).cloned().collect(),
+            None => Vec::new(),
+        };
+    }
+
+    let (piece, distance): (Option<Vec<String>>, Option<Vec<String>>) = tokio::join!(
+        find_piece_equals(channel_id.clone(), partial.clone()),
+        find_levenstein_distance(channel_id.clone(), partial.clone(), threshold)
+    );
+
+    let mut candidates: Vec<String> = piece.unwrap_or_default();
+    for word in distance.unwrap_or_default() {
+        if !candidates.contains(&word) {
+            candidates.push(word);
+        }
+    }
+
+    let rank = |word: &String| -> (u8, usize) {
+        let group = if word.starts_with(&partial) {
+            0
+        } else if word.contains(&partial) {
+            1
+        } else {
+            2
+        };
+        (group, edit_distance(word, &partial))
+    };
+
+    candidates.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| a.cmp(b)));
+    candidates.truncate(limit);
+    candidates
+}
+
+//queue.make_contiguous().reverse();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::test_environment;
+
+    #[tokio::test]
+    async fn only_the_current_player_can_play_once_someone_joined() {
+        test_environment();
+        let channel_id = "turn-test".to_string();
+        register(channel_id.clone(), None).await.unwrap();
+        assert_eq!(turn_error(&channel_id, "3").await, None);
+
+        for user_id in ["1", "2"] {
+            assert_eq!(add_user(channel_id.clone(), user_id.to_string()).await, Ok(true));
+        }
+
+        assert_eq!(turn_error(&channel_id, "1").await, None);
+        assert_eq!(turn_error(&channel_id, "2").await, Some("<@1> の手番です。".to_string()));
+        assert!(turn_error(&channel_id, "3").await.is_some_and(|error| error.contains("/shiritori join")));
+
+        assert_eq!(add_word(channel_id.clone(), "apple".to_string(), Some("1".to_string())).await, Ok(true));
+        assert_eq!(turn_error(&channel_id, "1").await, Some("<@2> の手番です。".to_string()));
+        assert_eq!(turn_error(&channel_id, "2").await, None);
+    }
+}
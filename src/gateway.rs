@@ -1,103 +1,300 @@
 use futures::stream::SplitStream;
 use futures::{SinkExt, StreamExt};
 use serde::{Serialize, Deserialize};
-use reqwest;
 use tokio::net::TcpStream;
 use std::path::Path;
 use std::sync::Arc;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
 use tokio::time::{self, Duration};
 use tokio::sync::Mutex;
 
-use crate::event::{check_mention_for_me, check_word, update_vote};
-use crate::game::{channel_exists, load_channel};
-use crate::utility::{self, verbose_log_async};
-use crate::spawn;
+use crate::event::{check_mention_for_me, check_word, discard_channel_votes, discard_vote, forget_channel, remember_channels, update_vote, warn_missing_content};
+use crate::interaction::handle_interaction;
+use crate::game::{assign_guild, channel_active, channel_exists, channels_in_guild, load_channel, threads_in_channel, unregister};
+use crate::model::{DispatchEvent, GatewayPayload};
+use crate::recorder::{record_gateway, send};
+use crate::utility::{self, is_bot_user, message_content_granted, set_identity, verbose_log_async, BotIdentity};
+use crate::{presence, shutdown};
+use crate::{spawn, spawn_tracked};
 
 #[derive(Serialize, Deserialize)]
 struct UrlResponse {
     url: String
 }
 
-const HARTBEAT_REQUEST: &str = "{\"op\":1, \"d\":null}";
 type StreamLock = Arc<Mutex<futures::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, Message>>>;
 
+lazy_static::lazy_static! {
+    static ref GATEWAY_WRITER: Mutex<Option<StreamLock>> = Mutex::new(None);
+}
+
 pub async fn login_bot() {
     let config = &utility::CONFIG;
-    let client = reqwest::Client::new();
-    let gateway_url = "https://discordapp.com/api/gateway";
-
-    let url_raw_response = client.get(gateway_url).send().await.unwrap();
+    let url_raw_response = send(utility::DISCORD_CLIENT.get(&config.gateway_url)).await.unwrap();
     let url_response: UrlResponse = url_raw_response.json().await.unwrap();
 
-    let ws_url = url_response.url;
-    let identify = format!(
-        r#"{{"op": 2, "d": {{"token": "{}", "properties": {{"os": "linux", "device": "device", "browser": "browser"}}, "intents": 1536}}}}"#,
-        config.token
-    );
-
-    let (ws_stream, _) = connect_async(&ws_url).await.expect("Failed to connect to gateway");
-    let (write, read) = ws_stream.split();
-    let write: StreamLock = Arc::new(Mutex::new(write));
-    println!("Connected to gateway at {}", ws_url);
-
-    {
-        let mut write_stream = write.lock().await;
-        verbose_log_async(format!("Sending identify: {}", identify).as_str()).await;
-        write_stream.send(Message::text(identify)).await.expect("Failed to send identify");
-    }
-
-    spawn!(main_loop(write, read));
+    spawn!(run_gateway(url_response.url));
     
     spawn!(registry_for());
 
 }
 
-async fn main_loop(write: StreamLock, mut read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>) {
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+enum Disconnect {
+    Resume,
+    Identify,
+    Stop,
+}
+
+// What a new connection needs to pick up where the last one stopped.
+struct Session {
+    id: String,
+    resume_url: Option<String>,
+    sequence: Arc<Mutex<Option<u64>>>,
+}
+
+fn identify_request() -> String {
+    serde_json::json!({
+        "op": 2,
+        "d": {
+            "token": utility::CONFIG.token,
+            "properties": {"os": "linux", "device": "device", "browser": "browser"},
+            "intents": utility::identify_intents(),
+        }
+    }).to_string()
+}
+
+async fn resume_request(session: &Session) -> String {
+    serde_json::json!({
+        "op": 6,
+        "d": {
+            "token": utility::CONFIG.token,
+            "session_id": session.id,
+            "seq": *session.sequence.lock().await,
+        }
+    }).to_string()
+}
+
+async fn run_gateway(ws_url: String) {
+    let mut session: Option<Session> = None;
+    let mut resume = false;
+
     loop {
-        let stream = read.next().await.expect("Failed to receive message from gateway");
+        if !resume {
+            session = None;
+        }
+        let url = session.as_ref().and_then(|session| session.resume_url.clone()).unwrap_or_else(|| ws_url.clone());
+
+        let (ws_stream, _) = match connect_async(&url).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                println!("Failed to connect to gateway: {}", e);
+                time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        let (write, read) = ws_stream.split();
+        let write: StreamLock = Arc::new(Mutex::new(write));
+        println!("Connected to gateway at {}", url);
+
+        {
+            let (kind, request) = match session.as_ref() {
+                Some(session) => ("resume", resume_request(session).await),
+                None => ("identify", identify_request()),
+            };
+            let mut write_stream = write.lock().await;
+            verbose_log_async(format!("Sending {}: {}", kind, request).as_str()).await;
+            if let Err(e) = write_stream.send(Message::text(request)).await {
+                println!("Failed to send {}: {}", kind, e);
+                drop(write_stream);
+                time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        }
+
+        {
+            let mut writer = GATEWAY_WRITER.lock().await;
+            *writer = Some(write.clone());
+        }
+
+        let disconnect = main_loop(write, read, &mut session).await;
+        if shutdown::is_shutting_down() {
+            return;
+        }
+
+        resume = match disconnect {
+            Disconnect::Resume => session.is_some(),
+            Disconnect::Identify => false,
+            Disconnect::Stop => return,
+        };
+        println!("Reconnecting to gateway ({})", if resume { "resume" } else { "identify" });
+        time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+pub async fn send_payload(payload: String) -> Result<(), ()> {
+    let writer = match GATEWAY_WRITER.lock().await.as_ref() {
+        Some(writer) => writer.clone(),
+        None => return Err(()),
+    };
+
+    let mut write_stream = writer.lock().await;
+    match write_stream.send(Message::text(payload)).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            verbose_log_async(format!("Failed to send gateway payload: {}", e).as_str()).await;
+            Err(())
+        }
+    }
+}
+
+pub async fn close() {
+    let writer = GATEWAY_WRITER.lock().await.take();
+
+    if let Some(writer) = writer {
+        let mut write_stream = writer.lock().await;
+        let close_frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "Shutting down".into(),
+        };
+
+        match write_stream.send(Message::Close(Some(close_frame))).await {
+            Ok(_) => println!("Sent close frame to gateway"),
+            Err(e) => println!("Failed to send close frame: {}", e),
+        }
+        let _ = write_stream.close().await;
+    }
+}
+
+async fn main_loop(write: StreamLock, mut read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>, session: &mut Option<Session>) -> Disconnect {
+    let sequence = session.as_ref().map_or_else(|| Arc::new(Mutex::new(None::<u64>)), |session| session.sequence.clone());
+    let mut heartbeat: Option<tokio::task::JoinHandle<()>> = None;
+    let disconnect = read_gateway(&write, &mut read, &sequence, &mut heartbeat, session).await;
+
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.abort();
+    }
+    disconnect
+}
+
+async fn read_gateway(
+    write: &StreamLock,
+    read: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    sequence: &Arc<Mutex<Option<u64>>>,
+    heartbeat: &mut Option<tokio::task::JoinHandle<()>>,
+    session: &mut Option<Session>,
+) -> Disconnect {
+
+    while let Some(stream) = read.next().await {
         match stream {
             Ok(message) => match message {
                 Message::Text(text) => {
-                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
-                    
-                    let op = json["op"].as_u64().unwrap();
-
-                    if op == 10 {
-                        let heartbeat_interval = json["d"]["heartbeat_interval"].as_u64().unwrap();
-                        let lock_clone = write.clone();
-                        let mut interval = time::interval(Duration::from_millis(heartbeat_interval));
-
-                        tokio::spawn(async move {
-                            loop {
-                                interval.tick().await;
-                                
-                                let mut write_stream = lock_clone.lock().await;
-                                write_stream.send(Message::text(HARTBEAT_REQUEST)).await.unwrap();
-
-                                verbose_log_async("Sent heartbeat").await;
+                    record_gateway(&text).await;
+
+                    let payload = match GatewayPayload::parse(&text) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            verbose_log_async(format!("Failed to parse gateway payload: {}\n{}", e, text).as_str()).await;
+                            continue;
+                        }
+                    };
+
+                    match payload {
+                        GatewayPayload::Hello { heartbeat_interval } => {
+                            let lock_clone = write.clone();
+                            let sequence = sequence.clone();
+                            let mut interval = time::interval(Duration::from_millis(heartbeat_interval));
+
+                            *heartbeat = Some(tokio::spawn(async move {
+                                loop {
+                                    interval.tick().await;
+
+                                    let heartbeat = heartbeat_request(*sequence.lock().await);
+                                    let mut write_stream = lock_clone.lock().await;
+                                    if let Err(e) = write_stream.send(Message::text(heartbeat)).await {
+                                        verbose_log_async(format!("Failed to send heartbeat: {}", e).as_str()).await;
+                                        break;
+                                    }
+
+                                    verbose_log_async("Sent heartbeat").await;
+                                }
+                            }));
+                        }
+                        GatewayPayload::Dispatch { seq, event } => {
+                            if seq.is_some() {
+                                *sequence.lock().await = seq;
+                            }
+                            if let DispatchEvent::Ready(ready) = event.as_ref() {
+                                *session = Some(Session { id: ready.session_id.clone(), resume_url: ready.resume_gateway_url.clone(), sequence: sequence.clone() });
+                            }
+                            if shutdown::is_shutting_down() {
+                                continue;
                             }
-                        });
-                    } else if op == 0 {
-                        event_handler(json).await;
+                            event_handler(*event).await;
+                        }
+                        GatewayPayload::Heartbeat => {
+                            let heartbeat = heartbeat_request(*sequence.lock().await);
+                            let mut write_stream = write.lock().await;
+                            let _ = write_stream.send(Message::text(heartbeat)).await;
+                        }
+                        GatewayPayload::HeartbeatAck => {}
+                        GatewayPayload::Reconnect => {
+                            println!("Gateway requested reconnect");
+                            return Disconnect::Resume;
+                        }
+                        GatewayPayload::InvalidSession { resumable } => {
+                            println!("Gateway invalidated the session (resumable: {})", resumable);
+                            if resumable {
+                                return Disconnect::Resume;
+                            }
+                            // Discord asks for a short random wait before identifying again.
+                            time::sleep(Duration::from_secs(2)).await;
+                            return Disconnect::Identify;
+                        }
+                        GatewayPayload::Unknown { op } => {
+                            verbose_log_async(format!("Unknown gateway opcode: {}", op).as_str()).await;
+                        }
                     }
                 }
                 Message::Close(Some(close_frame)) => {
                     println!("Gateway closed with code: {}, reason: {}", close_frame.code, close_frame.reason);
-                    break;
+                    return match u16::from(close_frame.code) {
+                        4014 if utility::refuse_message_content() => {
+                            println!("MESSAGE_CONTENT intent is not granted, identifying again without it. Words have to be played with /word");
+                            Disconnect::Identify
+                        }
+                        4014 => {
+                            println!("Disallowed intents: enable the privileged intents in the developer portal or disable them with INTENT_MESSAGE_CONTENT / INTENT_MEMBERS");
+                            Disconnect::Stop
+                        }
+                        // Authentication failed, invalid shard, sharding required, invalid API version or invalid intents.
+                        4004 | 4010..=4013 => Disconnect::Stop,
+                        // Invalid sequence or session timed out.
+                        4007 | 4009 => Disconnect::Identify,
+                        _ => Disconnect::Resume,
+                    };
                 }
                 _ => {}
             }
             Err(e) => {
                 println!("Error: {}", e);
-                break;
+                return Disconnect::Resume;
             }
         }
     }
+
+    Disconnect::Resume
+}
+
+fn heartbeat_request(sequence: Option<u64>) -> String {
+    serde_json::json!({ "op": 1, "d": sequence }).to_string()
 }
 
-async fn registry_for() {
+pub async fn registry_for() {
     verbose_log_async("Registering channels...").await;
     let current_dir = std::env::current_dir().unwrap();
     verbose_log_async(format!("Current directory: {}", current_dir.display()).as_str()).await;
@@ -130,35 +327,139 @@ async fn registry_for() {
     }
 }
 
-async fn event_handler(event: serde_json::Value) {
-    let event_type = event["t"].as_str().unwrap_or("");
-    match event_type {
-        "MESSAGE_CREATE" => {
-            if event["d"]["author"]["bot"] != true {
-                if check_mention_for_me(&event).await.is_ok() { return; }
+pub async fn event_handler(event: DispatchEvent) {
+    match event {
+        DispatchEvent::MessageCreate(message) => {
+            let author = match message.author.as_ref() {
+                Some(author) => author,
+                None => return,
+            };
+
+            if author.bot != Some(true) && !is_bot_user(&author.id).await {
+                if check_mention_for_me(&message).await.is_ok() { return; }
 
                 verbose_log_async("Message received").await;
-                let channel_id = match event["d"]["channel_id"].as_str() {
-                    Some(channel_id) => channel_id,
-                    None => return
-                };
 
-                if channel_exists(&channel_id).await == true {
+                if channel_active(&message.channel_id).await {
                     verbose_log_async("Channel active").await;
-                    let content = event["d"]["content"].as_str().unwrap();
-                    spawn!(check_word(content.to_string(), channel_id.to_string()));
+
+                    if message.content.is_empty() && message.attachments.is_empty() {
+                        if !message_content_granted().await {
+                            spawn!(warn_missing_content(message.channel_id, message.id));
+                        }
+                        return;
+                    }
+
+                    spawn_tracked!(check_word(message.content, message.channel_id, Some(author.id.clone()), Some(message.id)));
                 }
             }
         }
 
-        "MESSAGE_REACTION_ADD" => {
-            println!("Reaction added");
-            let _ = update_vote(&event["d"]).await;
+        DispatchEvent::MessageReactionAdd(reaction) => {
+            if is_bot_user(&reaction.user_id).await { return; }
+
+            verbose_log_async("Reaction added").await;
+            spawn_tracked!(async move { update_vote(&reaction).await });
+        }
+
+        DispatchEvent::MessageDelete(deleted) => {
+            discard_vote(&deleted.id).await;
+        }
+
+        DispatchEvent::Ready(ready) => {
+            let identity = BotIdentity {
+                user_id: ready.user.id.clone(),
+                username: ready.user.username.clone(),
+                application_id: ready.application.id.clone(),
+                application_flags: ready.application.flags.unwrap_or(0),
+            };
+
+            println!("Logged in as {} ({}), session {}", identity.username, identity.user_id, ready.session_id);
+            if identity.application_id != utility::CONFIG.app_id {
+                println!("DISCORD_APP_ID {} does not match the logged in application {}", utility::CONFIG.app_id, identity.application_id);
+            }
+
+            set_identity(identity).await;
+
+            if utility::CONFIG.presence_enabled {
+                presence::refresh();
+            }
+        }
+
+        // A new connection starts without a presence, so it is sent again.
+        DispatchEvent::Resumed => {
+            println!("Gateway session resumed");
+            if utility::CONFIG.presence_enabled {
+                presence::refresh();
+            }
         }
 
-        _ => {
-            println!("Unknown event type: {}", event_type);
+        DispatchEvent::GuildCreate(guild) => {
+            verbose_log_async(format!("Guild available: {} ({})", guild.name.clone().unwrap_or_default(), guild.id).as_str()).await;
+
+            let channel_ids = guild.channels.iter().chain(guild.threads.iter()).map(|channel| channel.id.clone()).collect();
+            assign_guild(channel_ids, guild.id.clone()).await;
+            remember_channels(guild.channels.iter().chain(guild.threads.iter())).await;
+        }
+
+        DispatchEvent::GuildDelete(guild) => {
+            if guild.unavailable == Some(true) {
+                verbose_log_async(format!("Guild unavailable: {}", guild.id).as_str()).await;
+                return;
+            }
+
+            for channel_id in channels_in_guild(&guild.id).await {
+                remove_deleted_channel(channel_id).await;
+            }
+        }
+
+        DispatchEvent::MessageUpdate(update) => {
+            verbose_log_async(format!("Message updated: {} in {}", update.id, update.channel_id).as_str()).await;
+        }
+
+        DispatchEvent::MessageReactionRemove(reaction) => {
+            verbose_log_async(format!("Reaction removed from {} by {}", reaction.message_id, reaction.user_id).as_str()).await;
+        }
+
+        DispatchEvent::InteractionCreate(interaction) => {
+            verbose_log_async(format!("Interaction received: {} (type {})", interaction.id, interaction.r#type).as_str()).await;
+            spawn_tracked!(handle_interaction(*interaction));
+        }
+
+        DispatchEvent::ChannelDelete(channel) => {
+            verbose_log_async(format!("Channel deleted: {}", channel.id).as_str()).await;
+            // Threads go away with their parent without a THREAD_DELETE of their own.
+            for thread_id in threads_in_channel(&channel.id).await {
+                remove_deleted_channel(thread_id).await;
+            }
+            forget_channel(&channel.id).await;
+            remove_deleted_channel(channel.id).await;
+        }
+
+        DispatchEvent::ThreadCreate(thread) => {
+            remember_channels([&thread]).await;
+        }
+
+        DispatchEvent::ThreadDelete(thread) => {
+            verbose_log_async(format!("Thread deleted: {}", thread.id).as_str()).await;
+            forget_channel(&thread.id).await;
+            remove_deleted_channel(thread.id).await;
+        }
+
+        DispatchEvent::Unknown(event_type) => {
+            verbose_log_async(format!("Unknown event type: {}", event_type).as_str()).await;
         }
     }
-        
-}
\ No newline at end of file
+}
+
+async fn remove_deleted_channel(channel_id: String) {
+    if !channel_exists(&channel_id).await {
+        return;
+    }
+
+    discard_channel_votes(&channel_id).await;
+    match unregister(channel_id.clone(), utility::CONFIG.deleted_channel_data).await {
+        Ok(_) => println!("Removed deleted channel {} ({:?})", channel_id, utility::CONFIG.deleted_channel_data),
+        Err(e) => println!("Failed to remove deleted channel {}: {}", channel_id, e),
+    }
+}
@@ -0,0 +1,754 @@
+use tokio::time::Instant;
+use serde_json::json;
+
+use crate::commands::{Command, CommandOption};
+use crate::event::{archive_thread, challenge_word, check_word, discard_channel_votes, normalize_word, parse_word_list, start_thread_game};
+use crate::game::{add_user, channel_active, channel_exists, channel_settings, contains_word, find_levenstein_distance, find_piece_equals, get_channel, import_words, mark_challenged, register, remove_user, reset_channel, set_active, skip_turn, suggest_words, thread_parent, turn_error, unregister, update_settings, word_history, ChannelSettings, Removal, Settings, WordEntry};
+use crate::interaction::{InteractionContext, Router, CALLBACK_CHANNEL_MESSAGE, CALLBACK_MODAL, CALLBACK_UPDATE_MESSAGE, MAX_AUTOCOMPLETE_CHOICES, PERMISSION_MANAGE_CHANNELS};
+use crate::message::{ActionRow, Button, MessageBuilder, BUTTON_SECONDARY};
+use crate::spawn_tracked;
+use crate::utility::{verbose_log_async, HTTP_CLIENT};
+
+pub fn router() -> Router {
+    Router::new()
+        .command(ping_command(), ping)
+        .command(word_command(), word)
+        .command(shiritori_command(), shiritori)
+        .command(lookup_command(), lookup)
+        .autocomplete("lookup", lookup_autocomplete)
+        .command(history_command(), history)
+        .component("history", history_page_button)
+        .command(config_command(), config)
+        .command(challenge_command(), challenge)
+        .command(import_command(), import)
+        .modal("import", import_modal)
+}
+
+fn ping_command() -> Command {
+    Command::slash("ping", "Ping the bot")
+}
+
+async fn ping(ctx: InteractionContext) {
+    let started = Instant::now();
+    if ctx.defer(true).await.is_ok() {
+        let _ = ctx.edit_original(format!("Pong! ({} ms)", started.elapsed().as_millis()).as_str()).await;
+    }
+}
+
+fn word_command() -> Command {
+    Command::slash("word", "Play a word")
+        .option(CommandOption::string("word", "The word to play").required().max_length(100))
+}
+
+async fn word(ctx: InteractionContext) {
+    let (word, channel_id) = match (ctx.option_str("word"), ctx.channel_id()) {
+        (Some(word), Some(channel_id)) => (word.to_string(), channel_id.to_string()),
+        _ => return,
+    };
+
+    if !channel_active(&channel_id).await {
+        let _ = ctx.respond("このチャンネルではゲームが開始されていません。", true).await;
+        return;
+    }
+
+    if normalize_word(&word).is_none() {
+        let _ = ctx.respond(format!("「{}」 は英単語として確認できません。", word).as_str(), true).await;
+        return;
+    }
+
+    let out_of_turn = match ctx.user_id() {
+        Some(user_id) => turn_error(&channel_id, user_id).await,
+        None => None,
+    };
+    if let Some(error) = out_of_turn {
+        let _ = ctx.respond(error.as_str(), true).await;
+        return;
+    }
+
+    if ctx.respond(format!("「{}」 を確認します。", word).as_str(), false).await.is_ok() {
+        spawn_tracked!(check_word(word, channel_id, ctx.user_id().map(str::to_string), None));
+    }
+}
+
+fn shiritori_command() -> Command {
+    Command::slash("shiritori", "Control the shiritori game")
+        .option(
+            CommandOption::subcommand("start", "Start a game in this channel")
+                .option(CommandOption::boolean("thread", "Run the game in a new thread")),
+        )
+        .option(CommandOption::subcommand("stop", "Stop the game in this channel"))
+        .option(CommandOption::subcommand("status", "Show the game status"))
+        .option(CommandOption::subcommand("join", "Join the game"))
+        .option(CommandOption::subcommand("leave", "Leave the game"))
+        .option(CommandOption::subcommand("skip", "Skip the current player's turn"))
+        .option(CommandOption::subcommand("reset", "Clear the used words"))
+        .option(
+            CommandOption::subcommand("unregister", "Unregister this channel and archive or delete its data")
+                .option(CommandOption::boolean("confirm", "Set to True to confirm").required())
+                .option(
+                    CommandOption::string("mode", "What to do with the channel data")
+                        .choice("archive", "archive")
+                        .choice("delete", "delete"),
+                ),
+        )
+}
+
+async fn shiritori(ctx: InteractionContext) {
+    let channel_id = match ctx.channel_id() {
+        Some(channel_id) => channel_id.to_string(),
+        None => return,
+    };
+    let subcommand = ctx.subcommand().unwrap_or_default().to_string();
+
+    let moderator_only = matches!(subcommand.as_str(), "stop" | "skip" | "reset" | "unregister");
+    if moderator_only && !ctx.has_permission(PERMISSION_MANAGE_CHANNELS) {
+        let _ = ctx.respond("このコマンドにはチャンネル管理権限が必要です。", true).await;
+        return;
+    }
+
+    if subcommand != "start" && !channel_exists(&channel_id).await {
+        let _ = ctx.respond("このチャンネルは登録されていません。/shiritori start で開始してください。", true).await;
+        return;
+    }
+
+    match subcommand.as_str() {
+        "start" if ctx.option_bool("thread") == Some(true) => {
+            let _ = match start_thread_game(&channel_id, ctx.interaction.guild_id.clone()).await {
+                Ok(thread_id) => ctx.respond(format!("<#{}> でしりとりを開始しました。", thread_id).as_str(), false).await,
+                Err(_) => ctx.respond("スレッドの作成に失敗しました。", true).await,
+            };
+        }
+        "start" => {
+            let result = if channel_exists(&channel_id).await {
+                set_active(channel_id, true).await
+            } else {
+                register(channel_id, ctx.interaction.guild_id.clone()).await
+            };
+
+            let _ = match result {
+                Ok(_) => ctx.respond("しりとりを開始しました。", false).await,
+                Err(_) => ctx.respond("開始に失敗しました。", true).await,
+            };
+        }
+        "stop" => {
+            let in_thread = thread_parent(&channel_id).await.is_some();
+            match set_active(channel_id.clone(), false).await {
+                Ok(_) if in_thread => {
+                    let _ = ctx.respond("しりとりを停止し、スレッドをアーカイブしました。", true).await;
+                    archive_thread(&channel_id).await;
+                }
+                Ok(_) => {
+                    let _ = ctx.respond("しりとりを停止しました。", true).await;
+                }
+                Err(_) => {
+                    let _ = ctx.respond("停止に失敗しました。", true).await;
+                }
+            }
+        }
+        "status" => {
+            let channel = match get_channel(&channel_id).await {
+                Some(channel) => channel,
+                None => return,
+            };
+
+            let state = if channel.active { "進行中" } else { "停止中" };
+            let word_count = channel.words.as_ref().map_or(0, |words| words.len());
+            let players = if channel.users.is_empty() {
+                "なし".to_string()
+            } else {
+                channel.users.iter().map(|user| format!("<@{}>", user)).collect::<Vec<_>>().join(", ")
+            };
+            let turn = channel.users.front().map_or("なし".to_string(), |user| format!("<@{}>", user));
+
+            let status = format!("状態: {}\n使用単語数: {}\n参加者: {}\n現在の手番: {}", state, word_count, players, turn);
+            let _ = ctx.respond(status.as_str(), true).await;
+        }
+        "join" | "leave" => {
+            let user_id = match ctx.user_id() {
+                Some(user_id) => user_id.to_string(),
+                None => return,
+            };
+
+            let response = if subcommand == "join" {
+                match add_user(channel_id, user_id.clone()).await {
+                    Ok(true) => format!("<@{}> が参加しました。", user_id),
+                    Ok(false) => "既に参加しています。".to_string(),
+                    Err(_) => "参加に失敗しました。".to_string(),
+                }
+            } else {
+                match remove_user(channel_id, user_id.clone()).await {
+                    Ok(true) => format!("<@{}> が退出しました。", user_id),
+                    Ok(false) => "参加していません。".to_string(),
+                    Err(_) => "退出に失敗しました。".to_string(),
+                }
+            };
+
+            let _ = ctx.respond(response.as_str(), false).await;
+        }
+        "skip" => {
+            let _ = match skip_turn(channel_id).await {
+                Ok(Some(next)) => match ctx.respond("手番をスキップしました。", true).await {
+                    Ok(_) => ctx.followup(format!("手番がスキップされました。次は <@{}> です。", next).as_str(), false).await,
+                    Err(_) => Err(()),
+                },
+                Ok(None) => ctx.respond("参加者がいません。", true).await,
+                Err(_) => ctx.respond("スキップに失敗しました。", true).await,
+            };
+        }
+        "unregister" => {
+            if ctx.option_bool("confirm") != Some(true) {
+                let _ = ctx.respond("登録を解除するには confirm に True を指定してください。", true).await;
+                return;
+            }
+
+            let removal = ctx.option_str("mode").and_then(Removal::parse).unwrap_or(Removal::Archive);
+            let in_thread = thread_parent(&channel_id).await.is_some();
+            match unregister(channel_id.clone(), removal).await {
+                Ok(_) => {
+                    discard_channel_votes(&channel_id).await;
+                    let response = match removal {
+                        Removal::Archive => "登録を解除し、データをアーカイブしました。",
+                        Removal::Delete => "登録を解除し、データを削除しました。",
+                    };
+                    let _ = ctx.respond(response, true).await;
+                    // Archived last, a response sent into an archived thread would reopen it.
+                    if in_thread {
+                        archive_thread(&channel_id).await;
+                    }
+                }
+                Err(_) => {
+                    let _ = ctx.respond("登録の解除に失敗しました。", true).await;
+                }
+            }
+        }
+        "reset" => {
+            let _ = match reset_channel(channel_id).await {
+                Ok(_) => ctx.respond("使用単語をリセットしました。", true).await,
+                Err(_) => ctx.respond("リセットに失敗しました。", true).await,
+            };
+        }
+        _ => {
+            let _ = ctx.respond("不明なサブコマンドです。", true).await;
+        }
+    }
+}
+
+fn lookup_command() -> Command {
+    Command::slash("lookup", "Look up a word in this channel's used words")
+        .option(CommandOption::string("word", "The word to look up").required().max_length(100).autocomplete())
+}
+
+async fn lookup(ctx: InteractionContext) {
+    let (word, channel_id) = match (ctx.option_str("word"), ctx.channel_id()) {
+        (Some(word), Some(channel_id)) => (word.to_string(), channel_id.to_string()),
+        _ => return,
+    };
+
+    if !channel_exists(&channel_id).await {
+        let _ = ctx.respond("このチャンネルは登録されていません。", true).await;
+        return;
+    }
+
+    let word = match normalize_word(&word) {
+        Some(word) => word,
+        None => {
+            let _ = ctx.respond(format!("「{}」 は英単語として確認できません。", word).as_str(), true).await;
+            return;
+        }
+    };
+
+    let used = contains_word(channel_id.clone(), word.clone()).await;
+    let (piece, distance) = tokio::join!(
+        find_piece_equals(channel_id.clone(), word.clone()),
+        find_levenstein_distance(channel_id.clone(), word.clone(), channel_settings(&channel_id).await.similarity_threshold)
+    );
+
+    let mut similar = piece.unwrap_or_default();
+    for candidate in distance.unwrap_or_default() {
+        if !similar.contains(&candidate) {
+            similar.push(candidate);
+        }
+    }
+    similar.retain(|candidate| *candidate != word);
+
+    let mut response = if used {
+        format!("{} は既に使用されています。", word)
+    } else {
+        format!("{} は使用されていません。", word)
+    };
+
+    if !similar.is_empty() {
+        let list: Vec<String> = similar.iter().map(|s| format!(" - {}", s)).collect();
+        response = format!("{}\n近い単語\n{}", response, list.join("\n"));
+    }
+
+    let _ = ctx.respond(response.as_str(), true).await;
+}
+
+async fn lookup_autocomplete(ctx: InteractionContext) {
+    let partial = ctx.focused_option()
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    let suggestions = match ctx.channel_id() {
+        Some(channel_id) => {
+            let threshold = channel_settings(channel_id).await.similarity_threshold;
+            suggest_words(channel_id.to_string(), partial, threshold, MAX_AUTOCOMPLETE_CHOICES).await
+        }
+        None => Vec::new(),
+    };
+
+    let _ = ctx.autocomplete(suggestions).await;
+}
+
+const HISTORY_PAGE_SIZE: usize = 10;
+
+fn history_page(history: &[WordEntry], page: usize) -> MessageBuilder {
+    let pages = history.len().div_ceil(HISTORY_PAGE_SIZE).max(1);
+    let page = page.min(pages - 1);
+
+    let lines: Vec<String> = history
+        .iter()
+        .enumerate()
+        .skip(page * HISTORY_PAGE_SIZE)
+        .take(HISTORY_PAGE_SIZE)
+        .map(|(index, entry)| match entry.user_id.as_ref() {
+            Some(user_id) => format!("{}. {} - <@{}>", index + 1, entry.word, user_id),
+            None => format!("{}. {}", index + 1, entry.word),
+        })
+        .collect();
+
+    let content = if lines.is_empty() {
+        "使用された単語はまだありません。".to_string()
+    } else {
+        format!("使用単語 ({}/{} ページ, {} 語)\n{}", page + 1, pages, history.len(), lines.join("\n"))
+    };
+
+    MessageBuilder::new().content(content).component(
+        ActionRow::new()
+            .button(Button::new(BUTTON_SECONDARY, "前へ", format!("history:{}", page.saturating_sub(1))).disabled(page == 0))
+            .button(Button::new(BUTTON_SECONDARY, "次へ", format!("history:{}", page + 1)).disabled(page + 1 >= pages)),
+    )
+}
+
+fn history_command() -> Command {
+    Command::slash("history", "Show the words used in this channel")
+}
+
+async fn history(ctx: InteractionContext) {
+    let history = match ctx.channel_id() {
+        Some(channel_id) => word_history(channel_id).await,
+        None => None,
+    };
+
+    let history = match history {
+        Some(history) => history,
+        None => {
+            let _ = ctx.respond("このチャンネルは登録されていません。", true).await;
+            return;
+        }
+    };
+
+    let _ = ctx.respond_with(json!({ "type": CALLBACK_CHANNEL_MESSAGE, "data": history_page(&history, 0).ephemeral(true) })).await;
+}
+
+async fn history_page_button(ctx: InteractionContext) {
+    let page = ctx.custom_id()
+        .and_then(|custom_id| custom_id.split(':').nth(1))
+        .and_then(|page| page.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let history = match ctx.channel_id() {
+        Some(channel_id) => word_history(channel_id).await.unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    let _ = ctx.respond_with(json!({ "type": CALLBACK_UPDATE_MESSAGE, "data": history_page(&history, page) })).await;
+}
+
+const MAX_VOTE_COUNT: i64 = 25;
+
+fn describe_settings(settings: &Settings) -> String {
+    let enabled = |flag: bool| if flag { "有効" } else { "無効" };
+
+    format!(
+        "類似度のしきい値: {}\n必要な投票数: {}\ndictionary api での確認: {}\nweblio での確認: {}",
+        settings.similarity_threshold,
+        settings.vote_count,
+        enabled(settings.dictionary_check),
+        enabled(settings.weblio_check)
+    )
+}
+
+fn config_command() -> Command {
+    Command::slash("config", "Show or change this channel's game settings")
+        .option(CommandOption::subcommand("show", "Show the current settings"))
+        .option(
+            CommandOption::subcommand("similarity", "Set the similarity threshold for near-duplicate words")
+                .option(CommandOption::number("value", "Threshold between 0 and 1").required().range(0, 1)),
+        )
+        .option(
+            CommandOption::subcommand("votes", "Set how many votes decide a word")
+                .option(CommandOption::integer("value", "Number of votes").required().range(1, MAX_VOTE_COUNT as i32)),
+        )
+        .option(
+            CommandOption::subcommand("dictionary", "Enable or disable the dictionary api check")
+                .option(CommandOption::boolean("enabled", "Whether the check runs").required()),
+        )
+        .option(
+            CommandOption::subcommand("weblio", "Enable or disable the weblio check")
+                .option(CommandOption::boolean("enabled", "Whether the check runs").required()),
+        )
+        .option(CommandOption::subcommand("reset", "Reset all settings to the defaults"))
+}
+
+async fn config(ctx: InteractionContext) {
+    let channel_id = match ctx.channel_id() {
+        Some(channel_id) => channel_id.to_string(),
+        None => return,
+    };
+
+    if !channel_exists(&channel_id).await {
+        let _ = ctx.respond("このチャンネルは登録されていません。", true).await;
+        return;
+    }
+
+    let subcommand = ctx.subcommand().unwrap_or_default().to_string();
+    if subcommand == "show" {
+        let settings = channel_settings(&channel_id).await;
+        let _ = ctx.respond(describe_settings(&settings).as_str(), true).await;
+        return;
+    }
+
+    if !ctx.has_permission(PERMISSION_MANAGE_CHANNELS) {
+        let _ = ctx.respond("設定の変更にはチャンネル管理権限が必要です。", true).await;
+        return;
+    }
+
+    let result = match subcommand.as_str() {
+        "similarity" => match ctx.option_f64("value") {
+            Some(value) if (0.0..=1.0).contains(&value) => update_settings(channel_id, |settings| settings.similarity_threshold = Some(value)).await,
+            _ => {
+                let _ = ctx.respond("しきい値は 0 から 1 の範囲で指定してください。", true).await;
+                return;
+            }
+        },
+        "votes" => match ctx.option_i64("value") {
+            Some(value) if (1..=MAX_VOTE_COUNT).contains(&value) => update_settings(channel_id, |settings| settings.vote_count = Some(value as u8)).await,
+            _ => {
+                let _ = ctx.respond(format!("投票数は 1 から {} の範囲で指定してください。", MAX_VOTE_COUNT).as_str(), true).await;
+                return;
+            }
+        },
+        "dictionary" | "weblio" => match ctx.option_bool("enabled") {
+            Some(enabled) if subcommand == "dictionary" => update_settings(channel_id, |settings| settings.dictionary_check = Some(enabled)).await,
+            Some(enabled) => update_settings(channel_id, |settings| settings.weblio_check = Some(enabled)).await,
+            None => return,
+        },
+        "reset" => update_settings(channel_id, |settings| *settings = ChannelSettings::default()).await,
+        _ => {
+            let _ = ctx.respond("不明なサブコマンドです。", true).await;
+            return;
+        }
+    };
+
+    let _ = match result {
+        Ok(settings) => ctx.respond(format!("設定を更新しました。\n{}", describe_settings(&settings)).as_str(), true).await,
+        Err(_) => ctx.respond("設定の更新に失敗しました。", true).await,
+    };
+}
+
+fn challenge_command() -> Command {
+    Command::message("Challenge word")
+}
+
+async fn challenge(ctx: InteractionContext) {
+    let channel_id = match ctx.channel_id() {
+        Some(channel_id) => channel_id.to_string(),
+        None => return,
+    };
+
+    if !channel_exists(&channel_id).await {
+        let _ = ctx.respond("このチャンネルは登録されていません。", true).await;
+        return;
+    }
+
+    let data = ctx.interaction.data.clone().unwrap_or_default();
+    let message = data.target_id.as_ref()
+        .and_then(|target_id| data.resolved.as_ref()?.messages.get(target_id).cloned());
+    let message = match message {
+        Some(message) => message,
+        None => {
+            let _ = ctx.respond("対象のメッセージが見つかりません。", true).await;
+            return;
+        }
+    };
+
+    let word = match normalize_word(&message.content) {
+        Some(word) => word,
+        None => {
+            let _ = ctx.respond("このメッセージには単語が含まれていません。", true).await;
+            return;
+        }
+    };
+
+    if !contains_word(channel_id.clone(), word.clone()).await {
+        let _ = ctx.respond(format!("「{}」 は使用済みの単語ではありません。", word).as_str(), true).await;
+        return;
+    }
+
+    match mark_challenged(channel_id.clone(), word.clone()).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let _ = ctx.respond(format!("「{}」 には既に異議が申し立てられています。", word).as_str(), true).await;
+            return;
+        }
+        Err(_) => {
+            let _ = ctx.respond("異議の登録に失敗しました。", true).await;
+            return;
+        }
+    }
+
+    let guild_id = ctx.interaction.guild_id.clone().unwrap_or_else(|| "@me".to_string());
+    let message_link = format!("https://discord.com/channels/{}/{}/{}", guild_id, channel_id, message.id);
+    let author_id = message.author.as_ref().map(|author| author.id.clone());
+
+    let _ = ctx.respond(format!("「{}」 の投票を開始します。", word).as_str(), true).await;
+    spawn_tracked!(challenge_word(channel_id, word, author_id, message.id.clone(), message_link));
+}
+
+const IMPORT_TEXT_INPUT: &str = "words";
+const MAX_IMPORT_TEXT_LENGTH: u32 = 4000;
+const MAX_IMPORT_FILE_SIZE: u64 = 256 * 1024;
+
+fn import_command() -> Command {
+    Command::slash("import", "Add a list of already used words to this channel")
+        .option(CommandOption::attachment("file", "Text file with one word per line or comma separated words"))
+}
+
+async fn import(ctx: InteractionContext) {
+    let channel_id = match ctx.channel_id() {
+        Some(channel_id) => channel_id.to_string(),
+        None => return,
+    };
+
+    if !channel_exists(&channel_id).await {
+        let _ = ctx.respond("このチャンネルは登録されていません。", true).await;
+        return;
+    }
+
+    if !ctx.has_permission(PERMISSION_MANAGE_CHANNELS) {
+        let _ = ctx.respond("単語の一括登録にはチャンネル管理権限が必要です。", true).await;
+        return;
+    }
+
+    let attachment = match ctx.option_attachment("file") {
+        Some(attachment) => attachment.clone(),
+        None => {
+            let _ = ctx.respond_with(json!({
+                "type": CALLBACK_MODAL,
+                "data": {
+                    "custom_id": "import",
+                    "title": "使用済み単語の一括登録",
+                    "components": [{
+                        "type": 1,
+                        "components": [{
+                            "type": 4,
+                            "custom_id": IMPORT_TEXT_INPUT,
+                            "label": "単語 (改行またはカンマ区切り)",
+                            "style": 2,
+                            "max_length": MAX_IMPORT_TEXT_LENGTH,
+                            "required": true,
+                        }],
+                    }],
+                },
+            })).await;
+            return;
+        }
+    };
+
+    if attachment.size > MAX_IMPORT_FILE_SIZE {
+        let _ = ctx.respond(format!("ファイルが大きすぎます。{} KiB 以下のテキストファイルを指定してください。", MAX_IMPORT_FILE_SIZE / 1024).as_str(), true).await;
+        return;
+    }
+    if !attachment.content_type.as_deref().is_some_and(|content_type| content_type.starts_with("text/")) {
+        let _ = ctx.respond("テキストファイルを指定してください。", true).await;
+        return;
+    }
+
+    let _ = ctx.defer(true).await;
+
+    let text = match HTTP_CLIENT.get(attachment.url.as_str()).send().await.and_then(|res| res.error_for_status()) {
+        Ok(res) => res.text().await.unwrap_or_default(),
+        Err(e) => {
+            verbose_log_async(format!("Failed to download {}: {}", attachment.filename, e).as_str()).await;
+            let _ = ctx.edit_original("ファイルの取得に失敗しました。").await;
+            return;
+        }
+    };
+
+    let report = import_word_list(channel_id, &text).await;
+    let _ = ctx.edit_original(report.as_str()).await;
+}
+
+async fn import_modal(ctx: InteractionContext) {
+    let channel_id = match ctx.channel_id() {
+        Some(channel_id) => channel_id.to_string(),
+        None => return,
+    };
+
+    if !ctx.has_permission(PERMISSION_MANAGE_CHANNELS) {
+        let _ = ctx.respond("単語の一括登録にはチャンネル管理権限が必要です。", true).await;
+        return;
+    }
+
+    let text = ctx.modal_value(IMPORT_TEXT_INPUT).unwrap_or_default().to_string();
+    let report = import_word_list(channel_id, &text).await;
+    let _ = ctx.respond(report.as_str(), true).await;
+}
+
+async fn import_word_list(channel_id: String, text: &str) -> String {
+    let (words, invalid) = parse_word_list(text);
+    let total = words.len();
+
+    match import_words(channel_id, words).await {
+        Ok(added) => format!(
+            "{} 件の単語を追加しました。\n使用済みまたは重複のためスキップ: {} 件\n形式が不正なためスキップ: {} 件",
+            added, total - added, invalid
+        ),
+        Err(_) => "単語の登録に失敗しました。".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::commands::{COMMAND_MESSAGE, OPTION_ATTACHMENT, OPTION_BOOLEAN, OPTION_INTEGER, OPTION_NUMBER, OPTION_STRING, OPTION_SUB_COMMAND};
+    use crate::interaction::{FakeResponder, INTERACTION_APPLICATION_COMMAND, INTERACTION_AUTOCOMPLETE};
+    use crate::mock::MockServer;
+    use crate::model::Interaction;
+    use crate::utility::test_environment;
+
+    fn option_value(option: &serde_json::Value) -> serde_json::Value {
+        if let Some(choice) = option["choices"].get(0) {
+            return choice["value"].clone();
+        }
+
+        match option["type"].as_u64().unwrap_or_default() as u8 {
+            OPTION_STRING => json!("apple"),
+            OPTION_INTEGER => option.get("min_value").cloned().unwrap_or(json!(1)),
+            OPTION_NUMBER => json!(0.5),
+            OPTION_BOOLEAN => json!(true),
+            OPTION_ATTACHMENT => json!("1"),
+            _ => serde_json::Value::Null,
+        }
+    }
+
+    fn fill_options(options: &serde_json::Value, focused: bool) -> Vec<serde_json::Value> {
+        options.as_array().into_iter().flatten()
+            .map(|option| {
+                let mut filled = json!({ "name": option["name"], "type": option["type"], "value": option_value(option) });
+                if focused && option["autocomplete"] == json!(true) {
+                    filled["focused"] = json!(true);
+                }
+                filled
+            })
+            .collect()
+    }
+
+    // Every option the definition declares is filled in, so a handler reading anything else panics.
+    fn invocations(definition: &Command, r#type: u8) -> Vec<serde_json::Value> {
+        let definition = serde_json::to_value(definition).unwrap();
+        let options = definition["options"].as_array().cloned().unwrap_or_default();
+        let subcommands: Vec<_> = options.iter().filter(|option| option["type"] == json!(OPTION_SUB_COMMAND)).collect();
+        let focused = r#type == INTERACTION_AUTOCOMPLETE;
+
+        if subcommands.is_empty() {
+            return vec![json!(fill_options(&definition["options"], focused))];
+        }
+        subcommands.into_iter()
+            .map(|subcommand| json!([{ "name": subcommand["name"], "type": OPTION_SUB_COMMAND, "options": fill_options(&subcommand["options"], focused) }]))
+            .collect()
+    }
+
+    fn interaction(definition: &Command, r#type: u8, channel_id: &str, options: serde_json::Value) -> Interaction {
+        let definition = serde_json::to_value(definition).unwrap();
+        serde_json::from_value(json!({
+            "id": "1",
+            "application_id": "2",
+            "type": r#type,
+            "token": "token",
+            "guild_id": "3",
+            "channel_id": channel_id,
+            "member": { "user": { "id": "4", "username": "player" }, "permissions": "8" },
+            "data": {
+                "name": definition["name"],
+                "options": options,
+                "target_id": "5",
+                "resolved": {
+                    "messages": { "5": { "type": 0, "id": "5", "channel_id": channel_id, "content": "apple", "author": { "id": "4", "username": "player" } } },
+                    "attachments": { "1": { "id": "1", "filename": "words.txt", "url": "http://127.0.0.1:9/words.txt", "size": 10, "content_type": "text/plain" } },
+                },
+            },
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_command_reads_only_declared_options() {
+        test_environment();
+        let router = router();
+        let mut channel = 0;
+
+        for definition in router.definitions() {
+            let kind = serde_json::to_value(&definition).unwrap()["type"].as_u64().unwrap_or_default() as u8;
+            let types: &[u8] = if kind == COMMAND_MESSAGE { &[INTERACTION_APPLICATION_COMMAND] } else { &[INTERACTION_APPLICATION_COMMAND, INTERACTION_AUTOCOMPLETE] };
+
+            for &r#type in types {
+                for options in invocations(&definition, r#type) {
+                    channel += 1;
+                    let channel_id = format!("routes-{}", channel);
+                    register(channel_id.clone(), Some("3".to_string())).await.unwrap();
+
+                    let responder = Arc::new(FakeResponder::default());
+                    let result = router.dispatch(interaction(&definition, r#type, &channel_id, options.clone()), responder.clone()).await;
+
+                    assert!(result.is_ok(), "/{} {} failed", definition.name(), options);
+                    assert!(!responder.calls.lock().unwrap().is_empty(), "/{} {} did not respond", definition.name(), options);
+                }
+            }
+        }
+    }
+    #[tokio::test]
+    async fn challenging_an_unused_word_is_refused() {
+        test_environment();
+        register("challenge-unused".to_string(), Some("3".to_string())).await.unwrap();
+
+        let responder = Arc::new(FakeResponder::default());
+        let challenge = interaction(&challenge_command(), INTERACTION_APPLICATION_COMMAND, "challenge-unused", json!([]));
+        assert!(router().dispatch(challenge, responder.clone()).await.is_ok());
+
+        assert_eq!(responder.calls.lock().unwrap()[0].1["data"]["content"], "「apple」 は使用済みの単語ではありません。");
+        assert!(!contains_word("challenge-unused".to_string(), "apple".to_string()).await);
+    }
+    #[tokio::test]
+    async fn failed_download_is_reported() {
+        test_environment();
+        let server = MockServer::start(0).await.unwrap();
+        register("import-missing".to_string(), Some("3".to_string())).await.unwrap();
+
+        let mut import = interaction(&import_command(), INTERACTION_APPLICATION_COMMAND, "import-missing", json!([{ "name": "file", "type": OPTION_ATTACHMENT, "value": "1" }]));
+        let resolved = import.data.as_mut().and_then(|data| data.resolved.as_mut()).unwrap();
+        resolved.attachments.get_mut("1").unwrap().url = format!("{}/channels/1/messages/missing", server.base_url());
+
+        let responder = Arc::new(FakeResponder::default());
+        assert!(router().dispatch(import, responder.clone()).await.is_ok());
+
+        let calls = responder.calls.lock().unwrap();
+        assert_eq!(calls.last().unwrap().0, "edit_original");
+        assert_eq!(calls.last().unwrap().1["content"], "ファイルの取得に失敗しました。");
+    }
+}
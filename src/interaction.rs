@@ -0,0 +1,566 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use lazy_static::lazy_static;
+use serde_json::json;
+
+use crate::commands::{Command, OPTION_SUB_COMMAND};
+use crate::handlers;
+use crate::message::{split_content, text_len, MessageBuilder, CONTENT_LIMIT};
+use crate::model::{Attachment, Interaction, InteractionOption};
+use crate::rest::{RestResult, REST};
+use crate::utility::verbose_log_async;
+
+pub const INTERACTION_PING: u8 = 1;
+pub const INTERACTION_APPLICATION_COMMAND: u8 = 2;
+pub const INTERACTION_MESSAGE_COMPONENT: u8 = 3;
+pub const INTERACTION_AUTOCOMPLETE: u8 = 4;
+pub const INTERACTION_MODAL_SUBMIT: u8 = 5;
+
+pub const CALLBACK_PONG: u8 = 1;
+pub const CALLBACK_CHANNEL_MESSAGE: u8 = 4;
+pub const CALLBACK_DEFERRED_CHANNEL_MESSAGE: u8 = 5;
+pub const CALLBACK_UPDATE_MESSAGE: u8 = 7;
+pub const CALLBACK_AUTOCOMPLETE_RESULT: u8 = 8;
+pub const CALLBACK_MODAL: u8 = 9;
+
+pub const MAX_AUTOCOMPLETE_CHOICES: usize = 25;
+const AUTOCOMPLETE_CHOICE_LIMIT: usize = 100;
+
+pub const EPHEMERAL: u64 = 1 << 6;
+
+pub const PERMISSION_ADMINISTRATOR: u64 = 1 << 3;
+pub const PERMISSION_MANAGE_CHANNELS: u64 = 1 << 4;
+
+pub type ResponseFuture<'a> = Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'a>>;
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type Handler = Arc<dyn Fn(InteractionContext) -> HandlerFuture + Send + Sync>;
+
+pub trait Responder: Send + Sync {
+    fn callback<'a>(&'a self, interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a>;
+    fn edit_original<'a>(&'a self, interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a>;
+    fn followup<'a>(&'a self, interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a>;
+}
+
+pub struct HttpResponder;
+
+async fn log_failure(kind: &str, result: RestResult<()>) -> Result<(), ()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            verbose_log_async(format!("Interaction {} failed: {}", kind, e).as_str()).await;
+            Err(())
+        }
+    }
+}
+
+impl Responder for HttpResponder {
+    fn callback<'a>(&'a self, interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a> {
+        Box::pin(async move {
+            log_failure("callback", REST.create_interaction_response(&interaction.id, &interaction.token, &body).await).await
+        })
+    }
+
+    fn edit_original<'a>(&'a self, interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a> {
+        Box::pin(async move {
+            log_failure("edit", REST.edit_original_response(&interaction.application_id, &interaction.token, &body).await).await
+        })
+    }
+
+    fn followup<'a>(&'a self, interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a> {
+        Box::pin(async move {
+            log_failure("followup", REST.create_followup_message(&interaction.application_id, &interaction.token, &body).await).await
+        })
+    }
+}
+
+fn message_body(content: &str, ephemeral: bool) -> serde_json::Value {
+    serde_json::to_value(MessageBuilder::new().content(content).ephemeral(ephemeral)).unwrap_or_default()
+}
+
+pub struct InteractionContext {
+    pub interaction: Interaction,
+    responder: Arc<dyn Responder>,
+    definition: Option<Arc<Command>>,
+}
+
+impl InteractionContext {
+    pub fn new(interaction: Interaction, responder: Arc<dyn Responder>) -> Self {
+        Self { interaction, responder, definition: None }
+    }
+
+    pub fn command_name(&self) -> Option<&str> {
+        self.interaction.data.as_ref()?.name.as_deref()
+    }
+
+    pub fn custom_id(&self) -> Option<&str> {
+        self.interaction.data.as_ref()?.custom_id.as_deref()
+    }
+
+    pub fn channel_id(&self) -> Option<&str> {
+        self.interaction.channel_id.as_deref()
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        match self.interaction.member.as_ref().and_then(|member| member.user.as_ref()) {
+            Some(user) => Some(user.id.as_str()),
+            None => self.interaction.user.as_ref().map(|user| user.id.as_str()),
+        }
+    }
+
+    pub fn has_permission(&self, permission: u64) -> bool {
+        let permissions = self.interaction.member.as_ref()
+            .and_then(|member| member.permissions.as_ref())
+            .and_then(|permissions| permissions.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        permissions & (permission | PERMISSION_ADMINISTRATOR) != 0
+    }
+
+    pub fn subcommand(&self) -> Option<&str> {
+        self.interaction.data.as_ref()?.options.first()
+            .filter(|option| option.r#type == OPTION_SUB_COMMAND)
+            .map(|option| option.name.as_str())
+    }
+
+    pub fn options(&self) -> &[InteractionOption] {
+        let options = match self.interaction.data.as_ref() {
+            Some(data) => data.options.as_slice(),
+            None => &[],
+        };
+
+        match options.first() {
+            Some(option) if option.r#type == OPTION_SUB_COMMAND => &option.options,
+            _ => options,
+        }
+    }
+
+    // Options missing from the command definition are never sent by Discord, so reading one is a handler bug.
+    fn option_value(&self, name: &str) -> Option<&serde_json::Value> {
+        if let Some(definition) = self.definition.as_ref() {
+            if !definition.declares_option(self.subcommand(), name) {
+                let problem = format!("/{} {} reads option {} which it does not declare", definition.name(), self.subcommand().unwrap_or_default(), name);
+                if cfg!(test) {
+                    panic!("{}", problem);
+                }
+                println!("{}", problem);
+                return None;
+            }
+        }
+
+        self.options()
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| option.value.as_ref())
+    }
+
+    pub fn option_str(&self, name: &str) -> Option<&str> {
+        self.option_value(name)
+            .and_then(|value| value.as_str())
+    }
+
+    pub fn option_attachment(&self, name: &str) -> Option<&Attachment> {
+        let id = self.option_str(name)?;
+        self.interaction.data.as_ref()?.resolved.as_ref()?.attachments.get(id)
+    }
+
+    pub fn modal_value(&self, custom_id: &str) -> Option<&str> {
+        self.interaction.data.as_ref()?.components
+            .iter()
+            .flat_map(|row| row.components.iter())
+            .find(|component| component.custom_id == custom_id)
+            .and_then(|component| component.value.as_deref())
+    }
+
+    pub fn option_f64(&self, name: &str) -> Option<f64> {
+        self.option_value(name)
+            .and_then(|value| value.as_f64())
+    }
+
+    pub fn option_i64(&self, name: &str) -> Option<i64> {
+        self.option_value(name)
+            .and_then(|value| value.as_i64())
+    }
+
+    pub fn option_bool(&self, name: &str) -> Option<bool> {
+        self.option_value(name)
+            .and_then(|value| value.as_bool())
+    }
+
+    pub fn focused_option(&self) -> Option<&InteractionOption> {
+        self.options().iter().find(|option| option.focused == Some(true))
+    }
+
+    // The choice is also its value, so a word too long for Discord is left out instead of truncated.
+    pub async fn autocomplete(&self, choices: Vec<String>) -> Result<(), ()> {
+        let choices: Vec<_> = choices
+            .into_iter()
+            .filter(|choice| text_len(choice) <= AUTOCOMPLETE_CHOICE_LIMIT)
+            .take(MAX_AUTOCOMPLETE_CHOICES)
+            .map(|choice| json!({ "name": choice, "value": choice }))
+            .collect();
+
+        self.respond_with(json!({ "type": CALLBACK_AUTOCOMPLETE_RESULT, "data": { "choices": choices } })).await
+    }
+
+    // Content over the message limit continues in follow-up messages.
+    pub async fn respond(&self, content: &str, ephemeral: bool) -> Result<(), ()> {
+        let mut chunks = split_content(content, CONTENT_LIMIT).into_iter();
+        let first = chunks.next().unwrap_or_default();
+        self.respond_with(json!({ "type": CALLBACK_CHANNEL_MESSAGE, "data": message_body(&first, ephemeral) })).await?;
+        self.followup_chunks(chunks, ephemeral).await
+    }
+
+    pub async fn respond_with(&self, body: serde_json::Value) -> Result<(), ()> {
+        self.responder.callback(&self.interaction, body).await
+    }
+
+    pub async fn defer(&self, ephemeral: bool) -> Result<(), ()> {
+        let flags = if ephemeral { EPHEMERAL } else { 0 };
+        self.respond_with(json!({ "type": CALLBACK_DEFERRED_CHANNEL_MESSAGE, "data": { "flags": flags } })).await
+    }
+
+    pub async fn edit_original(&self, content: &str) -> Result<(), ()> {
+        let mut chunks = split_content(content, CONTENT_LIMIT).into_iter();
+        let first = chunks.next().unwrap_or_default();
+        self.responder.edit_original(&self.interaction, message_body(&first, false)).await?;
+        self.followup_chunks(chunks, false).await
+    }
+
+    pub async fn followup(&self, content: &str, ephemeral: bool) -> Result<(), ()> {
+        self.followup_chunks(split_content(content, CONTENT_LIMIT).into_iter(), ephemeral).await
+    }
+
+    async fn followup_chunks(&self, chunks: impl Iterator<Item = String>, ephemeral: bool) -> Result<(), ()> {
+        for chunk in chunks {
+            self.responder.followup(&self.interaction, message_body(&chunk, ephemeral)).await?;
+        }
+        Ok(())
+    }
+}
+
+struct Route {
+    definition: Arc<Command>,
+    handler: Handler,
+}
+
+#[derive(Default)]
+pub struct Router {
+    commands: Vec<Route>,
+    autocompletes: HashMap<String, Handler>,
+    components: HashMap<String, Handler>,
+    modals: HashMap<String, Handler>,
+}
+
+fn boxed_handler<F, Fut>(handler: F) -> Handler
+where
+    F: Fn(InteractionContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |ctx| Box::pin(handler(ctx)) as HandlerFuture)
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn command<F, Fut>(mut self, definition: Command, handler: F) -> Self
+    where
+        F: Fn(InteractionContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.commands.push(Route { definition: Arc::new(definition), handler: boxed_handler(handler) });
+        self
+    }
+
+    pub fn definitions(&self) -> Vec<Command> {
+        self.commands.iter().map(|route| route.definition.as_ref().clone()).collect()
+    }
+
+    fn route(&self, name: Option<&str>) -> Option<&Route> {
+        self.commands.iter().find(|route| Some(route.definition.name()) == name)
+    }
+
+    pub fn autocomplete<F, Fut>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(InteractionContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.autocompletes.insert(name.to_string(), boxed_handler(handler));
+        self
+    }
+
+    pub fn component<F, Fut>(mut self, prefix: &str, handler: F) -> Self
+    where
+        F: Fn(InteractionContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.components.insert(prefix.to_string(), boxed_handler(handler));
+        self
+    }
+
+    pub fn modal<F, Fut>(mut self, prefix: &str, handler: F) -> Self
+    where
+        F: Fn(InteractionContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.modals.insert(prefix.to_string(), boxed_handler(handler));
+        self
+    }
+
+    pub async fn dispatch(&self, interaction: Interaction, responder: Arc<dyn Responder>) -> Result<(), ()> {
+        let mut ctx = InteractionContext::new(interaction, responder);
+        let route = match ctx.interaction.r#type {
+            INTERACTION_APPLICATION_COMMAND | INTERACTION_AUTOCOMPLETE => self.route(ctx.command_name()),
+            _ => None,
+        };
+        ctx.definition = route.map(|route| route.definition.clone());
+
+        match ctx.interaction.r#type {
+            INTERACTION_PING => ctx.respond_with(json!({ "type": CALLBACK_PONG })).await,
+            INTERACTION_APPLICATION_COMMAND => {
+                match route.map(|route| route.handler.clone()) {
+                    Some(handler) => {
+                        handler(ctx).await;
+                        Ok(())
+                    }
+                    None => {
+                        verbose_log_async(format!("No handler for command {:?}", ctx.command_name()).as_str()).await;
+                        let _ = ctx.respond("このコマンドは利用できません。", true).await;
+                        Err(())
+                    }
+                }
+            }
+            INTERACTION_MESSAGE_COMPONENT => {
+                let prefix = ctx.custom_id().and_then(|custom_id| custom_id.split(':').next());
+                let handler = prefix.and_then(|prefix| self.components.get(prefix)).cloned();
+
+                match handler {
+                    Some(handler) => {
+                        handler(ctx).await;
+                        Ok(())
+                    }
+                    None => {
+                        verbose_log_async(format!("No handler for component {:?}", ctx.custom_id()).as_str()).await;
+                        Err(())
+                    }
+                }
+            }
+            INTERACTION_MODAL_SUBMIT => {
+                let prefix = ctx.custom_id().and_then(|custom_id| custom_id.split(':').next());
+                let handler = prefix.and_then(|prefix| self.modals.get(prefix)).cloned();
+
+                match handler {
+                    Some(handler) => {
+                        handler(ctx).await;
+                        Ok(())
+                    }
+                    None => {
+                        verbose_log_async(format!("No handler for modal {:?}", ctx.custom_id()).as_str()).await;
+                        let _ = ctx.respond("このフォームは利用できません。", true).await;
+                        Err(())
+                    }
+                }
+            }
+            INTERACTION_AUTOCOMPLETE => {
+                let handler = ctx.command_name().and_then(|name| self.autocompletes.get(name)).cloned();
+
+                match handler {
+                    Some(handler) => {
+                        handler(ctx).await;
+                        Ok(())
+                    }
+                    None => ctx.autocomplete(Vec::new()).await,
+                }
+            }
+            other => {
+                verbose_log_async(format!("Unhandled interaction type: {}", other).as_str()).await;
+                Err(())
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref ROUTER: Router = handlers::router();
+}
+
+pub fn command_definitions() -> Vec<Command> {
+    ROUTER.definitions()
+}
+
+pub async fn handle_interaction(interaction: Interaction) {
+    let _ = ROUTER.dispatch(interaction, Arc::new(HttpResponder)).await;
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeResponder {
+    pub calls: std::sync::Mutex<Vec<(&'static str, serde_json::Value)>>,
+}
+
+#[cfg(test)]
+impl FakeResponder {
+    fn record(&self, kind: &'static str, body: serde_json::Value) -> ResponseFuture<'_> {
+        self.calls.lock().unwrap().push((kind, body));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+impl Responder for FakeResponder {
+    fn callback<'a>(&'a self, _interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a> {
+        self.record("callback", body)
+    }
+
+    fn edit_original<'a>(&'a self, _interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a> {
+        self.record("edit_original", body)
+    }
+
+    fn followup<'a>(&'a self, _interaction: &'a Interaction, body: serde_json::Value) -> ResponseFuture<'a> {
+        self.record("followup", body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandOption;
+
+    fn interaction(payload: serde_json::Value) -> Interaction {
+        serde_json::from_value(payload).unwrap()
+    }
+
+    fn command(name: &str, options: serde_json::Value) -> Interaction {
+        interaction(json!({
+            "id": "1",
+            "application_id": "2",
+            "type": INTERACTION_APPLICATION_COMMAND,
+            "token": "token",
+            "channel_id": "3",
+            "data": { "name": name, "options": options },
+        }))
+    }
+
+    fn test_router() -> Router {
+        Router::new()
+            .command(Command::slash("echo", "Echo").option(CommandOption::string("text", "Text")), |ctx| async move {
+                let text = ctx.option_str("text").unwrap_or_default().to_string();
+                let _ = ctx.respond(&text, true).await;
+            })
+            .command(Command::slash("slow", "Slow"), |ctx| async move {
+                let _ = ctx.defer(false).await;
+                let _ = ctx.edit_original("done").await;
+                let _ = ctx.followup("more", true).await;
+            })
+    }
+
+    #[tokio::test]
+    async fn routes_command_with_options() {
+        let responder = Arc::new(FakeResponder::default());
+        let result = test_router().dispatch(command("echo", json!([{ "name": "text", "type": 3, "value": "hello" }])), responder.clone()).await;
+
+        assert!(result.is_ok());
+        let calls = responder.calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, "callback");
+        assert_eq!(calls[0].1["type"], CALLBACK_CHANNEL_MESSAGE);
+        assert_eq!(calls[0].1["data"]["content"], "hello");
+        assert_eq!(calls[0].1["data"]["flags"], EPHEMERAL);
+    }
+
+    #[tokio::test]
+    async fn deferred_command_edits_and_follows_up() {
+        let responder = Arc::new(FakeResponder::default());
+        let result = test_router().dispatch(command("slow", json!([])), responder.clone()).await;
+
+        assert!(result.is_ok());
+        let calls = responder.calls.lock().unwrap();
+        let kinds: Vec<_> = calls.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, ["callback", "edit_original", "followup"]);
+        assert_eq!(calls[0].1["type"], CALLBACK_DEFERRED_CHANNEL_MESSAGE);
+        assert_eq!(calls[1].1["content"], "done");
+        assert_eq!(calls[2].1["content"], "more");
+        assert_eq!(calls[2].1["flags"], EPHEMERAL);
+    }
+
+    #[tokio::test]
+    async fn long_response_continues_in_followups() {
+        let responder = Arc::new(FakeResponder::default());
+        let text = vec!["word"; 1000].join("\n");
+        let result = test_router().dispatch(command("echo", json!([{ "name": "text", "type": 3, "value": text }])), responder.clone()).await;
+
+        assert!(result.is_ok());
+        let calls = responder.calls.lock().unwrap();
+        let kinds: Vec<_> = calls.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, ["callback", "followup", "followup"]);
+        assert_eq!(calls[1].1["flags"], EPHEMERAL);
+        let sent: Vec<_> = calls.iter().map(|(_, body)| body["content"].as_str().or(body["data"]["content"].as_str()).unwrap()).collect();
+        assert_eq!(sent.join("\n"), text);
+    }
+
+    #[tokio::test]
+    async fn unknown_command_gets_ephemeral_error() {
+        let responder = Arc::new(FakeResponder::default());
+        let result = test_router().dispatch(command("missing", json!([])), responder.clone()).await;
+
+        assert!(result.is_err());
+        let calls = responder.calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].1["data"]["flags"], EPHEMERAL);
+    }
+
+    #[tokio::test]
+    async fn autocomplete_leaves_out_overlong_choices() {
+        let responder = Arc::new(FakeResponder::default());
+        let ctx = InteractionContext::new(command("lookup", json!([])), responder.clone());
+        let mut choices = vec!["a".repeat(AUTOCOMPLETE_CHOICE_LIMIT + 1)];
+        choices.extend((0..30).map(|i| format!("word{}", i)));
+        assert!(ctx.autocomplete(choices).await.is_ok());
+
+        let calls = responder.calls.lock().unwrap();
+        let sent = calls[0].1["data"]["choices"].as_array().unwrap();
+        assert_eq!(sent.len(), MAX_AUTOCOMPLETE_CHOICES);
+        assert_eq!(sent[0]["value"], "word0");
+    }
+
+    #[tokio::test]
+    async fn ping_interaction_gets_pong() {
+        let responder = Arc::new(FakeResponder::default());
+        let ping = interaction(json!({ "id": "1", "application_id": "2", "type": INTERACTION_PING, "token": "token" }));
+        let result = test_router().dispatch(ping, responder.clone()).await;
+
+        assert!(result.is_ok());
+        let calls = responder.calls.lock().unwrap();
+        assert_eq!(calls[0].1["type"], CALLBACK_PONG);
+    }
+
+    fn game_router() -> Router {
+        let definition = Command::slash("game", "Game")
+            .option(CommandOption::subcommand("start", "Start").option(CommandOption::boolean("thread", "Thread")))
+            .option(CommandOption::subcommand("stop", "Stop"));
+        Router::new().command(definition, |ctx| async move {
+            let thread = ctx.option_bool("thread").map_or("none".to_string(), |thread| thread.to_string());
+            let _ = ctx.respond(&thread, true).await;
+        })
+    }
+
+    fn game(subcommand: &str) -> Interaction {
+        command("game", json!([{ "name": subcommand, "type": OPTION_SUB_COMMAND, "options": [{ "name": "thread", "type": 5, "value": true }] }]))
+    }
+
+    #[tokio::test]
+    async fn options_are_read_from_the_invoked_subcommand() {
+        let responder = Arc::new(FakeResponder::default());
+        assert!(game_router().dispatch(game("start"), responder.clone()).await.is_ok());
+        assert_eq!(responder.calls.lock().unwrap()[0].1["data"]["content"], "true");
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "reads option thread which it does not declare")]
+    async fn reading_an_undeclared_option_fails_under_test() {
+        let _ = game_router().dispatch(game("stop"), Arc::new(FakeResponder::default())).await;
+    }
+}
@@ -1,8 +1,19 @@
 mod utility;
 mod commands;
+mod rest;
+mod ratelimit;
 mod gateway;
 mod event;
 mod game;
+mod model;
+mod message;
+mod shutdown;
+mod recorder;
+mod mock;
+mod replay;
+mod presence;
+mod interaction;
+mod handlers;
 
 #[macro_export]
 macro_rules! spawn {
@@ -11,16 +22,60 @@ macro_rules! spawn {
     };
 }
 
+#[macro_export]
+macro_rules! spawn_tracked {
+    ($task:expr) => {
+        tokio::spawn($crate::shutdown::track($task));
+    };
+}
+
 #[tokio::main]
 async fn main() {
     utility::verbose_log_async("Starting NS Shiritori...").await;
 
-    match commands::register_commands().await {
-        Ok(_) => println!("Commands registered successfully!"),
-        Err(_) => println!("Error registering commands"),
+    if std::env::args().any(|arg| arg == "--write-commands") {
+        let written = commands::write_commands_file().await;
+        std::process::exit(if written.is_ok() { 0 } else { 1 });
+    }
+
+    if let Some(replay_file) = utility::CONFIG.replay_file.as_ref() {
+        println!("Replaying {} against a local mock server", replay_file);
+        if replay::run(replay_file).await.is_err() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let dry_run = std::env::args().any(|arg| arg == "--dry-run-commands");
+    match commands::sync_commands(dry_run).await {
+        Ok(_) if dry_run => println!("Dry run finished, no commands were changed"),
+        Ok(_) => println!("Commands synced successfully!"),
+        Err(_) => println!("Error syncing commands"),
     }
 
+    if dry_run {
+        return;
+    }
+
+    event::load_votes().await;
     let _ = gateway::login_bot().await;
 
-    loop {}
+    if utility::CONFIG.presence_enabled {
+        spawn!(presence::run());
+    }
+
+    shutdown::wait_for_signal().await;
+    println!("Shutting down NS Shiritori...");
+    shutdown::begin();
+
+    let timeout = std::time::Duration::from_secs(utility::CONFIG.shutdown_timeout);
+    if !shutdown::wait_idle(timeout).await {
+        println!("Timed out waiting for in-flight tasks after {} seconds", utility::CONFIG.shutdown_timeout);
+    }
+
+    game::save_all_channels().await;
+    event::save_votes().await;
+    gateway::close().await;
+
+    println!("Shutdown complete");
 }
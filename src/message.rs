@@ -0,0 +1,312 @@
+use serde::Serialize;
+
+use crate::interaction::EPHEMERAL;
+
+pub const BUTTON_SECONDARY: u8 = 2;
+
+const COMPONENT_ACTION_ROW: u8 = 1;
+const COMPONENT_BUTTON: u8 = 2;
+
+pub const CONTENT_LIMIT: usize = 2000;
+const EMBED_TITLE_LIMIT: usize = 256;
+const EMBED_FIELD_NAME_LIMIT: usize = 256;
+const EMBED_FIELD_VALUE_LIMIT: usize = 1024;
+const EMBED_FOOTER_LIMIT: usize = 2048;
+const EMBED_FIELDS_LIMIT: usize = 25;
+const EMBED_TOTAL_LIMIT: usize = 6000;
+const ELLIPSIS: char = '…';
+
+// Discord measures lengths in UTF-16 code units.
+pub fn text_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+pub fn truncate(text: &str, limit: usize) -> String {
+    if text_len(text) <= limit {
+        return text.to_string();
+    }
+
+    let mut truncated = String::new();
+    let mut len = 0;
+    for c in text.chars() {
+        if len + c.len_utf16() > limit.saturating_sub(1) {
+            break;
+        }
+        len += c.len_utf16();
+        truncated.push(c);
+    }
+    if limit > 0 {
+        truncated.push(ELLIPSIS);
+    }
+    truncated
+}
+
+// Splits on line breaks where possible so each chunk can be sent as its own message.
+pub fn split_content(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for line in text.split('\n') {
+        let separator = if current.is_empty() { 0 } else { 1 };
+        if !current.is_empty() && text_len(&current) + separator + text_len(line) > limit {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        for c in line.chars() {
+            if text_len(&current) + c.len_utf16() > limit {
+                chunks.push(std::mem::take(&mut current));
+            }
+            current.push(c);
+        }
+    }
+
+    if !current.is_empty() || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+// Only the mentions listed here ping anyone, so user supplied text can never ping by itself.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct AllowedMentions {
+    parse: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    users: Vec<String>,
+    replied_user: bool,
+}
+
+impl AllowedMentions {
+    pub fn user(mut self, user_id: &str) -> Self {
+        self.users.push(user_id.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MessageReference {
+    message_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel_id: Option<String>,
+    fail_if_not_exists: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EmbedFooter {
+    text: String,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Embed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<EmbedField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    footer: Option<EmbedFooter>,
+}
+
+impl Embed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn len(&self) -> usize {
+        self.title.as_deref().map_or(0, text_len)
+            + self.fields.iter().map(|field| text_len(&field.name) + text_len(&field.value)).sum::<usize>()
+            + self.footer.as_ref().map_or(0, |footer| text_len(&footer.text))
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(truncate(&title.into(), EMBED_TITLE_LIMIT));
+        self
+    }
+
+    // Fields past the count limit are dropped and values are cut to whatever the total limit leaves.
+    pub fn field(mut self, name: impl Into<String>, value: impl Into<String>, inline: bool) -> Self {
+        if self.fields.len() >= EMBED_FIELDS_LIMIT {
+            return self;
+        }
+
+        let name = truncate(&name.into(), EMBED_FIELD_NAME_LIMIT);
+        let remaining = EMBED_TOTAL_LIMIT.saturating_sub(self.len() + text_len(&name));
+        let value = truncate(&value.into(), EMBED_FIELD_VALUE_LIMIT.min(remaining));
+        if !value.is_empty() {
+            self.fields.push(EmbedField { name, value, inline });
+        }
+        self
+    }
+
+    pub fn footer(mut self, text: impl Into<String>) -> Self {
+        let remaining = EMBED_TOTAL_LIMIT.saturating_sub(self.len());
+        self.footer = Some(EmbedFooter { text: truncate(&text.into(), EMBED_FOOTER_LIMIT.min(remaining)) });
+        self
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Button {
+    #[serde(rename = "type")]
+    r#type: u8,
+    style: u8,
+    label: String,
+    custom_id: String,
+    disabled: bool,
+}
+
+impl Button {
+    pub fn new(style: u8, label: impl Into<String>, custom_id: impl Into<String>) -> Self {
+        Self { r#type: COMPONENT_BUTTON, style, label: label.into(), custom_id: custom_id.into(), disabled: false }
+    }
+
+    pub fn disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        self
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ActionRow {
+    #[serde(rename = "type")]
+    r#type: u8,
+    components: Vec<Button>,
+}
+
+impl ActionRow {
+    pub fn new() -> Self {
+        Self { r#type: COMPONENT_ACTION_ROW, components: Vec::new() }
+    }
+
+    pub fn button(mut self, button: Button) -> Self {
+        self.components.push(button);
+        self
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct MessageBuilder {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    embeds: Vec<Embed>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    components: Vec<ActionRow>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_reference: Option<MessageReference>,
+    allowed_mentions: AllowedMentions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flags: Option<u64>,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(truncate(&content.into(), CONTENT_LIMIT));
+        self
+    }
+
+    pub fn embed(mut self, embed: Embed) -> Self {
+        self.embeds.push(embed);
+        self
+    }
+
+    pub fn component(mut self, row: ActionRow) -> Self {
+        self.components.push(row);
+        self
+    }
+
+    pub fn reply_to(mut self, message_id: &str) -> Self {
+        self.message_reference = Some(MessageReference { message_id: message_id.to_string(), channel_id: None, fail_if_not_exists: false });
+        self
+    }
+
+    pub fn allowed_mentions(mut self, allowed_mentions: AllowedMentions) -> Self {
+        self.allowed_mentions = allowed_mentions;
+        self
+    }
+
+    pub fn ephemeral(mut self, ephemeral: bool) -> Self {
+        self.flags = ephemeral.then_some(EPHEMERAL);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_text_is_escaped_and_never_pings() {
+        let message = MessageBuilder::new().content("\"quoted\" \\ @everyone\nnext");
+        let payload = serde_json::to_string(&message).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&payload).unwrap();
+
+        assert_eq!(parsed["content"], "\"quoted\" \\ @everyone\nnext");
+        assert_eq!(parsed["allowed_mentions"], serde_json::json!({ "parse": [], "replied_user": false }));
+    }
+
+    #[test]
+    fn only_allowed_users_are_pinged() {
+        let message = MessageBuilder::new().content("<@1> <@2>").allowed_mentions(AllowedMentions::default().user("1"));
+        let parsed = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(parsed["allowed_mentions"], serde_json::json!({ "parse": [], "users": ["1"], "replied_user": false }));
+    }
+
+    #[test]
+    fn reply_and_embed_are_serialized() {
+        let message = MessageBuilder::new()
+            .reply_to("10")
+            .embed(Embed::new().title("apple").field("weblio", "りんご", false));
+        let parsed = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(parsed["message_reference"]["message_id"], "10");
+        assert_eq!(parsed["message_reference"]["fail_if_not_exists"], false);
+        assert_eq!(parsed["embeds"][0]["fields"][0]["value"], "りんご");
+        assert!(parsed.get("content").is_none());
+    }
+
+    #[test]
+    fn long_text_is_truncated_to_discord_limits() {
+        assert_eq!(truncate("apple", 5), "apple");
+        assert_eq!(truncate("apples", 5), "appl…");
+
+        let message = MessageBuilder::new().content("あ".repeat(3000));
+        let parsed = serde_json::to_value(&message).unwrap();
+        assert_eq!(text_len(parsed["content"].as_str().unwrap()), CONTENT_LIMIT);
+
+        let mut embed = Embed::new().title("t".repeat(300));
+        for _ in 0..30 {
+            embed = embed.field("name", "v".repeat(2000), false);
+        }
+        let embed = embed.footer("footer");
+        assert!(embed.len() <= EMBED_TOTAL_LIMIT);
+        assert_eq!(text_len(embed.title.as_deref().unwrap()), EMBED_TITLE_LIMIT);
+        assert_eq!(text_len(&embed.fields[0].value), EMBED_FIELD_VALUE_LIMIT);
+        assert!(embed.fields.len() <= EMBED_FIELDS_LIMIT);
+    }
+
+    #[test]
+    fn long_content_is_split_on_lines() {
+        let text = (0..300).map(|i| format!("{}. word", i)).collect::<Vec<_>>().join("\n");
+        let chunks = split_content(&text, CONTENT_LIMIT);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| text_len(chunk) <= CONTENT_LIMIT));
+        assert_eq!(chunks.join("\n"), text);
+        assert_eq!(split_content(&"a".repeat(4500), CONTENT_LIMIT).len(), 3);
+        assert_eq!(split_content("", CONTENT_LIMIT), [""]);
+    }
+}
@@ -0,0 +1,532 @@
+// Kept free of crate imports so the integration tests can include this file as well.
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+
+pub const MOCK_BOT_USER_ID: &str = "1000";
+pub const MOCK_APPLICATION_ID: &str = "2000";
+const MOCK_HEARTBEAT_INTERVAL: u64 = 45000;
+
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+    pub status: u16,
+}
+
+struct MockBucket {
+    limit: u32,
+    remaining: u32,
+    reset_after: Duration,
+    reset_at: Instant,
+}
+
+#[derive(Default)]
+struct MockState {
+    requests: Vec<MockRequest>,
+    messages: HashMap<String, serde_json::Value>,
+    channels: HashMap<String, serde_json::Value>,
+    commands: serde_json::Value,
+    rate_limits: VecDeque<(f64, bool)>,
+    bucket: Option<MockBucket>,
+    delay: Option<Duration>,
+    gateway_url: String,
+    gateway_clients: Vec<mpsc::UnboundedSender<Message>>,
+    // Every payload the bot sent to the gateway except heartbeats.
+    gateway_payloads: Vec<serde_json::Value>,
+    rejected_intents: u64,
+    sequence: u64,
+}
+
+pub struct MockServer {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1_000_000_000_000_000_000);
+
+impl MockServer {
+    pub async fn start(port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let addr = listener.local_addr()?;
+        let gateway_listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let gateway_addr = gateway_listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            commands: serde_json::json!([]),
+            gateway_url: format!("ws://{}", gateway_addr),
+            ..Default::default()
+        }));
+
+        tokio::spawn(accept_loop(listener, state.clone(), |stream, state| tokio::spawn(handle_connection(stream, state))));
+        tokio::spawn(accept_loop(gateway_listener, state.clone(), |stream, state| tokio::spawn(handle_gateway(stream, state))));
+
+        Ok(Self { addr, state })
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}/api/v10", self.addr)
+    }
+
+    pub async fn requests(&self) -> Vec<MockRequest> {
+        self.state.lock().await.requests.clone()
+    }
+}
+
+// Only tests steer the server or inspect what the bot sent, a replay just serves the recording.
+#[cfg(test)]
+impl MockServer {
+    // The next request is answered with a 429 asking the client to wait `retry_after` seconds.
+    pub async fn rate_limit_next(&self, retry_after: f64, global: bool) {
+        self.state.lock().await.rate_limits.push_back((retry_after, global));
+    }
+
+    // Every route shares one bucket of `limit` requests per `reset_after`, going over it is answered with a 429.
+    pub async fn limit_bucket(&self, limit: u32, reset_after: Duration) {
+        self.state.lock().await.bucket = Some(MockBucket { limit, remaining: limit, reset_after, reset_at: Instant::now() + reset_after });
+    }
+
+    // Every response is held back this long, like a slow Discord.
+    pub async fn delay_responses(&self, delay: Duration) {
+        self.state.lock().await.delay = Some(delay);
+    }
+
+    pub async fn message(&self, message_id: &str) -> Option<serde_json::Value> {
+        self.state.lock().await.messages.get(message_id).cloned()
+    }
+
+    // Every message the bot has posted, oldest first.
+    pub async fn messages(&self) -> Vec<serde_json::Value> {
+        let state = self.state.lock().await;
+        let mut messages: Vec<_> = state.messages.values().cloned().collect();
+        messages.sort_by_key(|message| message["id"].as_str().and_then(|id| id.parse::<u64>().ok()));
+        messages
+    }
+}
+
+// The end to end tests include this file as well, they are the only ones driving the gateway or checking these.
+#[cfg(test)]
+#[allow(dead_code)]
+impl MockServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub async fn channel(&self, channel_id: &str) -> Option<serde_json::Value> {
+        self.state.lock().await.channels.get(channel_id).cloned()
+    }
+
+    pub async fn commands(&self) -> serde_json::Value {
+        self.state.lock().await.commands.clone()
+    }
+
+    // Stands in for a user reacting, the bot is only told about it once the test dispatches MESSAGE_REACTION_ADD.
+    pub async fn add_reaction(&self, message_id: &str, emoji: &str) {
+        if let Some(message) = self.state.lock().await.messages.get_mut(message_id) {
+            react(message, emoji, false);
+        }
+    }
+
+    // Identifies asking for any of these intents are closed with 4014, like a privileged intent that is not enabled.
+    pub async fn reject_intents(&self, intents: u64) {
+        self.state.lock().await.rejected_intents = intents;
+    }
+
+    // Asks every connection to reconnect with op 7, they stop receiving dispatches until they resume or identify.
+    pub async fn request_reconnect(&self) {
+        let mut state = self.state.lock().await;
+        for client in state.gateway_clients.drain(..) {
+            let _ = client.send(Message::text(serde_json::json!({ "op": 7, "d": null }).to_string()));
+        }
+    }
+
+    // Sends a dispatch event to every identified gateway connection.
+    pub async fn dispatch(&self, event: &str, data: serde_json::Value) {
+        let mut state = self.state.lock().await;
+        state.sequence += 1;
+        let payload = serde_json::json!({ "op": 0, "t": event, "s": state.sequence, "d": data }).to_string();
+        state.gateway_clients.retain(|client| client.send(Message::text(payload.clone())).is_ok());
+    }
+
+    pub async fn gateway_payloads(&self) -> Vec<serde_json::Value> {
+        self.state.lock().await.gateway_payloads.clone()
+    }
+
+    pub async fn wait_for_identify(&self, timeout: Duration) -> Option<serde_json::Value> {
+        self.wait_for_gateway(timeout, |payload| payload["op"] == 2).await.map(|identify| identify["d"].clone())
+    }
+
+    pub async fn wait_for_gateway(&self, timeout: Duration, matches: impl Fn(&serde_json::Value) -> bool) -> Option<serde_json::Value> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(payload) = self.state.lock().await.gateway_payloads.iter().find(|payload| matches(payload)) {
+                return Some(payload.clone());
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+
+    pub async fn wait_for_request(&self, timeout: Duration, matches: impl Fn(&MockRequest) -> bool) -> Option<MockRequest> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(request) = self.state.lock().await.requests.iter().find(|request| matches(request)) {
+                return Some(request.clone());
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+}
+
+async fn accept_loop<F>(listener: TcpListener, state: Arc<Mutex<MockState>>, handle: F)
+where
+    F: Fn(TcpStream, Arc<Mutex<MockState>>) -> tokio::task::JoinHandle<()>,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                handle(stream, state.clone());
+            }
+            Err(e) => eprintln!("Mock server failed to accept: {}", e),
+        }
+    }
+}
+
+// Answers Hello, Identify and heartbeats the way the real gateway does, everything else is sent with `dispatch`.
+async fn handle_gateway(stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let socket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Mock gateway handshake failed: {}", e);
+            return;
+        }
+    };
+    let (mut write, mut read) = socket.split();
+    let (sender, mut outgoing) = mpsc::unbounded_channel::<Message>();
+
+    tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let closing = matches!(message, Message::Close(_));
+            if write.send(message).await.is_err() || closing {
+                break;
+            }
+        }
+    });
+
+    let _ = sender.send(Message::text(serde_json::json!({ "op": 10, "d": { "heartbeat_interval": MOCK_HEARTBEAT_INTERVAL } }).to_string()));
+
+    while let Some(Ok(message)) = read.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let payload = serde_json::from_str::<serde_json::Value>(&text).unwrap_or_default();
+
+        match payload["op"].as_u64() {
+            Some(1) => {
+                let _ = sender.send(Message::text(serde_json::json!({ "op": 11 }).to_string()));
+            }
+            Some(2) => {
+                let mut state = state.lock().await;
+                state.gateway_payloads.push(payload.clone());
+                if payload["d"]["intents"].as_u64().unwrap_or(0) & state.rejected_intents != 0 {
+                    let _ = sender.send(Message::Close(Some(CloseFrame { code: CloseCode::from(4014), reason: "Disallowed intent(s).".into() })));
+                    break;
+                }
+                state.sequence += 1;
+                let ready = serde_json::json!({
+                    "op": 0,
+                    "t": "READY",
+                    "s": state.sequence,
+                    "d": {
+                        "v": 10,
+                        "user": { "id": MOCK_BOT_USER_ID, "username": "mock", "bot": true },
+                        "session_id": "mock",
+                        "resume_gateway_url": state.gateway_url,
+                        "application": { "id": MOCK_APPLICATION_ID, "flags": 1 << 18 },
+                    },
+                });
+                let _ = sender.send(Message::text(ready.to_string()));
+                state.gateway_clients.push(sender.clone());
+            }
+            Some(6) => {
+                let mut state = state.lock().await;
+                state.gateway_payloads.push(payload.clone());
+                state.sequence += 1;
+                let resumed = serde_json::json!({ "op": 0, "t": "RESUMED", "s": state.sequence, "d": {} });
+                let _ = sender.send(Message::text(resumed.to_string()));
+                state.gateway_clients.push(sender.clone());
+            }
+            _ => state.lock().await.gateway_payloads.push(payload),
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let mut request_line = String::new();
+        match reader.read_line(&mut request_line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            match reader.read_line(&mut header).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).await.is_err() {
+            return;
+        }
+
+        let delay = state.lock().await.delay;
+        if let Some(delay) = delay {
+            time::sleep(delay).await;
+        }
+
+        let mut request = MockRequest { method, path, body: String::from_utf8_lossy(&body).to_string(), status: 0 };
+        let (status, response_body, headers) = match rate_limit(&state).await {
+            Some(limited) => limited,
+            None => {
+                let (status, response_body) = route(&request, &state).await;
+                (status, response_body, bucket_headers(&state).await)
+            }
+        };
+        request.status = status;
+        state.lock().await.requests.push(request);
+
+        let content_type = if response_body.starts_with('<') { "text/html" } else { "application/json" };
+        let status_text = match status {
+            200 => "OK",
+            204 => "No Content",
+            404 => "Not Found",
+            429 => "Too Many Requests",
+            _ => "Unknown",
+        };
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}\r\n{}",
+            status, status_text, content_type, response_body.len(), headers, response_body
+        );
+
+        if reader.get_mut().write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn rate_limit(state: &Arc<Mutex<MockState>>) -> Option<(u16, String, String)> {
+    let mut state = state.lock().await;
+
+    if let Some((retry_after, global)) = state.rate_limits.pop_front() {
+        let body = serde_json::json!({ "message": "You are being rate limited.", "retry_after": retry_after, "global": global });
+        let mut headers = format!("Retry-After: {}\r\n", retry_after.ceil());
+        if global {
+            headers.push_str("X-RateLimit-Global: true\r\nX-RateLimit-Scope: global\r\n");
+        }
+        return Some((429, body.to_string(), headers));
+    }
+
+    let bucket = state.bucket.as_mut()?;
+    let now = Instant::now();
+    if now >= bucket.reset_at {
+        bucket.remaining = bucket.limit;
+        bucket.reset_at = now + bucket.reset_after;
+    }
+
+    if bucket.remaining == 0 {
+        let retry_after = (bucket.reset_at - now).as_secs_f64();
+        let body = serde_json::json!({ "message": "You are being rate limited.", "retry_after": retry_after, "global": false });
+        return Some((429, body.to_string(), "X-RateLimit-Bucket: mock\r\nX-RateLimit-Remaining: 0\r\nX-RateLimit-Scope: user\r\n".to_string()));
+    }
+
+    bucket.remaining -= 1;
+    None
+}
+
+async fn bucket_headers(state: &Arc<Mutex<MockState>>) -> String {
+    let state = state.lock().await;
+
+    match state.bucket.as_ref() {
+        Some(bucket) => format!(
+            "X-RateLimit-Bucket: mock\r\nX-RateLimit-Limit: {}\r\nX-RateLimit-Remaining: {}\r\nX-RateLimit-Reset-After: {}\r\n",
+            bucket.limit,
+            bucket.remaining,
+            (bucket.reset_at.saturating_duration_since(Instant::now()).as_secs_f64() * 1000.0).ceil() / 1000.0
+        ),
+        None => String::new(),
+    }
+}
+
+async fn route(request: &MockRequest, state: &Arc<Mutex<MockState>>) -> (u16, String) {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_start_matches("/api/v10").trim_matches('/').split('/').collect();
+    let body = serde_json::from_str::<serde_json::Value>(&request.body).unwrap_or_default();
+    let mut state = state.lock().await;
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["gateway"]) => (200, serde_json::json!({ "url": state.gateway_url }).to_string()),
+        ("GET", ["dictionary", word]) => (200, serde_json::json!([{ "word": word }]).to_string()),
+        ("GET", ["weblio", word]) => (200, format!(r#"<html><body><div class="content-explanation">{} の意味</div></body></html>"#, word)),
+        ("POST", ["channels", parent_id, "threads"]) => {
+            let thread_id = NEXT_ID.fetch_add(1, Ordering::SeqCst).to_string();
+            let thread = serde_json::json!({ "id": thread_id, "type": body["type"].as_u64().unwrap_or(11), "name": body["name"], "parent_id": parent_id });
+
+            state.channels.insert(thread_id, thread.clone());
+            (200, thread.to_string())
+        }
+        // Channels the mock did not create are plain text channels.
+        ("GET", ["channels", channel_id]) => {
+            let channel = state.channels.get(*channel_id).cloned().unwrap_or_else(|| serde_json::json!({ "id": channel_id, "type": 0 }));
+            (200, channel.to_string())
+        }
+        ("PATCH", ["channels", channel_id]) => {
+            let channel = state.channels.entry(channel_id.to_string()).or_insert_with(|| serde_json::json!({ "id": channel_id, "type": 0 }));
+            if let (Some(channel), Some(changes)) = (channel.as_object_mut(), body.as_object()) {
+                channel.extend(changes.clone());
+            }
+            (200, channel.to_string())
+        }
+        ("POST", ["channels", channel_id, "messages"]) => {
+            let message_id = NEXT_ID.fetch_add(1, Ordering::SeqCst).to_string();
+            let mut message = serde_json::json!({
+                "type": 0,
+                "id": message_id,
+                "channel_id": channel_id,
+                "content": "",
+                "mentions": [],
+                "reactions": [],
+            });
+            apply_message_fields(&mut message, &body);
+
+            state.messages.insert(message_id, message.clone());
+            (200, message.to_string())
+        }
+        ("PATCH", ["channels", _, "messages", message_id]) | ("GET", ["channels", _, "messages", message_id]) => {
+            match state.messages.get_mut(*message_id) {
+                Some(message) => {
+                    apply_message_fields(message, &body);
+                    (200, message.to_string())
+                }
+                None => (404, r#"{"code": 10008, "message": "Unknown Message"}"#.to_string()),
+            }
+        }
+        ("DELETE", ["channels", _, "messages", message_id]) => match state.messages.remove(*message_id) {
+            Some(_) => (204, String::new()),
+            None => (404, r#"{"code": 10008, "message": "Unknown Message"}"#.to_string()),
+        },
+        ("PUT", ["channels", _, "messages", message_id, "reactions", emoji, "@me"]) => {
+            if let Some(message) = state.messages.get_mut(*message_id) {
+                react(message, &percent_decode(emoji), true);
+            }
+            (204, String::new())
+        }
+        ("DELETE", ["channels", _, "messages", message_id, "reactions"]) => {
+            if let Some(message) = state.messages.get_mut(*message_id) {
+                message["reactions"] = serde_json::json!([]);
+            }
+            (204, String::new())
+        }
+        (_, ["channels", _, "messages", _, "reactions", ..]) => (204, String::new()),
+        ("POST", ["interactions", _, _, "callback"]) => (204, String::new()),
+        ("GET", ["applications", _, "commands"] | ["applications", _, "guilds", _, "commands"]) => {
+            (200, state.commands.to_string())
+        }
+        ("PUT", ["applications", _, "commands"] | ["applications", _, "guilds", _, "commands"]) => {
+            state.commands = body.clone();
+            (200, body.to_string())
+        }
+        _ => (200, "{}".to_string()),
+    }
+}
+
+fn apply_message_fields(message: &mut serde_json::Value, body: &serde_json::Value) {
+    for field in ["content", "embeds", "components", "message_reference", "allowed_mentions"] {
+        if !body[field].is_null() {
+            message[field] = body[field].clone();
+        }
+    }
+}
+
+fn react(message: &mut serde_json::Value, emoji: &str, me: bool) {
+    let reactions = match message["reactions"].as_array_mut() {
+        Some(reactions) => reactions,
+        None => return,
+    };
+
+    match reactions.iter_mut().find(|reaction| reaction["emoji"]["name"] == emoji) {
+        Some(reaction) => {
+            let count = reaction["count"].as_u64().unwrap_or(0) + 1;
+            reaction["count"] = count.into();
+            reaction["count_details"]["normal"] = count.into();
+            if me {
+                reaction["me"] = true.into();
+            }
+        }
+        None => reactions.push(serde_json::json!({
+            "count": 1,
+            "count_details": { "burst": 0, "normal": 1 },
+            "me": me,
+            "me_burst": false,
+            "emoji": { "id": null, "name": emoji },
+        })),
+    }
+}
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| segment.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Debug)]
+struct RawPayload {
+    op: u8,
+    #[serde(default)]
+    d: serde_json::Value,
+    s: Option<u64>,
+    t: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum GatewayPayload {
+    Dispatch { seq: Option<u64>, event: Box<DispatchEvent> },
+    Heartbeat,
+    Reconnect,
+    InvalidSession { resumable: bool },
+    Hello { heartbeat_interval: u64 },
+    HeartbeatAck,
+    Unknown { op: u8 },
+}
+
+#[derive(Debug)]
+pub enum DispatchEvent {
+    Ready(Box<Ready>),
+    Resumed,
+    MessageCreate(Box<Message>),
+    MessageUpdate(MessageUpdate),
+    MessageDelete(MessageDelete),
+    MessageReactionAdd(ReactionEvent),
+    MessageReactionRemove(ReactionEvent),
+    InteractionCreate(Box<Interaction>),
+    GuildCreate(Box<Guild>),
+    GuildDelete(UnavailableGuild),
+    ChannelDelete(PartialChannel),
+    ThreadCreate(PartialChannel),
+    ThreadDelete(PartialChannel),
+    Unknown(String),
+}
+
+#[derive(Deserialize)]
+struct Hello {
+    heartbeat_interval: u64,
+}
+
+impl GatewayPayload {
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        let raw: RawPayload = serde_json::from_str(text)?;
+
+        let payload = match raw.op {
+            0 => GatewayPayload::Dispatch {
+                seq: raw.s,
+                event: Box::new(DispatchEvent::from_parts(raw.t.unwrap_or_default(), raw.d)),
+            },
+            1 => GatewayPayload::Heartbeat,
+            7 => GatewayPayload::Reconnect,
+            9 => GatewayPayload::InvalidSession { resumable: raw.d.as_bool().unwrap_or(false) },
+            10 => {
+                let hello: Hello = serde_json::from_value(raw.d)?;
+                GatewayPayload::Hello { heartbeat_interval: hello.heartbeat_interval }
+            }
+            11 => GatewayPayload::HeartbeatAck,
+            op => GatewayPayload::Unknown { op },
+        };
+
+        Ok(payload)
+    }
+}
+
+impl DispatchEvent {
+    // An event that fails to decode is passed on as unknown, its sequence number still has to reach the gateway loop.
+    fn from_parts(event_type: String, d: serde_json::Value) -> Self {
+        Self::decode(&event_type, d).unwrap_or_else(|e| DispatchEvent::Unknown(format!("{} ({})", event_type, e)))
+    }
+
+    fn decode(event_type: &str, d: serde_json::Value) -> Result<Self, serde_json::Error> {
+        let event = match event_type {
+            "READY" => DispatchEvent::Ready(serde_json::from_value(d)?),
+            "RESUMED" => DispatchEvent::Resumed,
+            "MESSAGE_CREATE" => DispatchEvent::MessageCreate(serde_json::from_value(d)?),
+            "MESSAGE_UPDATE" => DispatchEvent::MessageUpdate(serde_json::from_value(d)?),
+            "MESSAGE_DELETE" => DispatchEvent::MessageDelete(serde_json::from_value(d)?),
+            "MESSAGE_REACTION_ADD" => DispatchEvent::MessageReactionAdd(serde_json::from_value(d)?),
+            "MESSAGE_REACTION_REMOVE" => DispatchEvent::MessageReactionRemove(serde_json::from_value(d)?),
+            "INTERACTION_CREATE" => DispatchEvent::InteractionCreate(serde_json::from_value(d)?),
+            "GUILD_CREATE" => DispatchEvent::GuildCreate(serde_json::from_value(d)?),
+            "GUILD_DELETE" => DispatchEvent::GuildDelete(serde_json::from_value(d)?),
+            "CHANNEL_DELETE" => DispatchEvent::ChannelDelete(serde_json::from_value(d)?),
+            "THREAD_CREATE" => DispatchEvent::ThreadCreate(serde_json::from_value(d)?),
+            "THREAD_DELETE" => DispatchEvent::ThreadDelete(serde_json::from_value(d)?),
+            _ => DispatchEvent::Unknown(event_type.to_string()),
+        };
+
+        Ok(event)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub global_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Emoji {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CountDetails {
+    pub burst: u8,
+    pub normal: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reaction {
+    pub count: u8,
+    pub count_details: CountDetails,
+    pub me: bool,
+    pub me_burst: bool,
+    pub emoji: Emoji,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    pub id: String,
+    pub filename: String,
+    pub url: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    #[serde(rename = "type")]
+    pub r#type: u8,
+    pub id: String,
+    pub channel_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<User>,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub mentions: Vec<User>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<Vec<Reaction>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MessageUpdate {
+    pub id: String,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    pub author: Option<User>,
+    pub content: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MessageDelete {
+    pub id: String,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReactionEvent {
+    pub user_id: String,
+    pub channel_id: String,
+    pub message_id: String,
+    pub guild_id: Option<String>,
+    pub emoji: Emoji,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartialApplication {
+    pub id: String,
+    pub flags: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ready {
+    pub v: u8,
+    pub user: User,
+    pub session_id: String,
+    pub resume_gateway_url: Option<String>,
+    pub application: PartialApplication,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interaction {
+    pub id: String,
+    pub application_id: String,
+    #[serde(rename = "type")]
+    pub r#type: u8,
+    pub data: Option<InteractionData>,
+    pub guild_id: Option<String>,
+    pub channel_id: Option<String>,
+    pub member: Option<Member>,
+    pub user: Option<User>,
+    pub token: String,
+    pub message: Option<Message>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InteractionData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<InteractionOption>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<ResolvedData>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<ActionRow>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResolvedData {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub messages: HashMap<String, Message>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub attachments: HashMap<String, Attachment>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActionRow {
+    #[serde(default)]
+    pub components: Vec<SubmittedComponent>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubmittedComponent {
+    #[serde(rename = "type")]
+    pub r#type: u8,
+    pub custom_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InteractionOption {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub r#type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<InteractionOption>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focused: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Guild {
+    pub id: String,
+    pub name: Option<String>,
+    pub unavailable: Option<bool>,
+    #[serde(default)]
+    pub channels: Vec<PartialChannel>,
+    #[serde(default)]
+    pub threads: Vec<PartialChannel>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnavailableGuild {
+    pub id: String,
+    pub unavailable: Option<bool>,
+}
+
+pub const THREAD_TYPES: [u8; 3] = [10, 11, 12];
+pub const PUBLIC_THREAD: u8 = 11;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartialChannel {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub r#type: u8,
+    pub guild_id: Option<String>,
+    pub parent_id: Option<String>,
+}
+
+impl PartialChannel {
+    // Other channels use parent_id for their category, only a thread's parent is a channel.
+    pub fn thread_parent(&self) -> Option<String> {
+        if THREAD_TYPES.contains(&self.r#type) {
+            self.parent_id.clone()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_event_keeps_its_sequence() {
+        let payload = GatewayPayload::parse(r#"{"op":0,"s":42,"t":"MESSAGE_CREATE","d":{"content":"apple"}}"#).unwrap();
+
+        match payload {
+            GatewayPayload::Dispatch { seq, event } => {
+                assert_eq!(seq, Some(42));
+                assert!(matches!(*event, DispatchEvent::Unknown(ref event_type) if event_type.starts_with("MESSAGE_CREATE")));
+            }
+            other => panic!("expected a dispatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_payloads_are_passed_on() {
+        let event = GatewayPayload::parse(r#"{"op":0,"s":7,"t":"TYPING_START","d":{}}"#).unwrap();
+        assert!(matches!(event, GatewayPayload::Dispatch { seq: Some(7), event } if matches!(*event, DispatchEvent::Unknown(ref event_type) if event_type == "TYPING_START")));
+
+        assert!(matches!(GatewayPayload::parse(r#"{"op":42,"d":null}"#), Ok(GatewayPayload::Unknown { op: 42 })));
+        assert!(GatewayPayload::parse("not json").is_err());
+        assert!(GatewayPayload::parse(r#"{"op":10,"d":{}}"#).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use lazy_static::lazy_static;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration, Instant};
+
+use crate::game::CHANNELS;
+use crate::gateway::send_payload;
+use crate::shutdown;
+use crate::utility::{verbose_log_async, CONFIG};
+
+const MIN_PRESENCE_INTERVAL: u64 = 20;
+
+lazy_static! {
+    static ref RESEND: Notify = Notify::new();
+}
+
+pub fn refresh() {
+    RESEND.notify_one();
+}
+
+async fn current_status() -> String {
+    let channels = CHANNELS.read().await;
+
+    let running = channels.values().filter(|channel| channel.active).count();
+    let longest = channels
+        .values()
+        .filter(|channel| channel.active)
+        .map(|channel| channel.words.as_ref().map_or(0, |words| words.len()))
+        .max()
+        .unwrap_or(0);
+
+    if running == 0 || longest == 0 {
+        "参加者を待っています".to_string()
+    } else {
+        format!("{} チャンネルでしりとり中 | 最長 {} 語", running, longest)
+    }
+}
+
+fn presence_payload(status: &str) -> String {
+    serde_json::json!({
+        "op": 3,
+        "d": {
+            "since": null,
+            "activities": [{ "name": "Custom Status", "type": 4, "state": status }],
+            "status": "online",
+            "afk": false,
+        }
+    }).to_string()
+}
+
+pub async fn run() {
+    let interval = Duration::from_secs(CONFIG.presence_interval.max(MIN_PRESENCE_INTERVAL));
+    let mut last_status: Option<String> = None;
+    let mut last_sent: Option<Instant> = None;
+
+    loop {
+        let forced = tokio::select! {
+            _ = time::sleep(interval) => false,
+            _ = RESEND.notified() => true,
+        };
+
+        if shutdown::is_shutting_down() {
+            return;
+        }
+
+        if let Some(last_sent) = last_sent {
+            let elapsed = last_sent.elapsed();
+            if elapsed < interval {
+                time::sleep(interval - elapsed).await;
+            }
+        }
+
+        let status = current_status().await;
+        if !forced && last_status.as_deref() == Some(status.as_str()) {
+            continue;
+        }
+
+        if send_payload(presence_payload(&status)).await.is_ok() {
+            verbose_log_async(format!("Updated presence: {}", status).as_str()).await;
+            last_status = Some(status);
+            last_sent = Some(Instant::now());
+        }
+    }
+}
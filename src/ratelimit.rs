@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use reqwest::header::HeaderMap;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{self, Duration, Instant};
+
+use crate::utility::verbose_log_async;
+
+#[derive(Default, Clone)]
+pub struct BucketState {
+    limit: Option<u32>,
+    remaining: Option<u32>,
+    reset_at: Option<Instant>,
+    // Set while the only request allowed on a bucket with unknown limits is in flight.
+    probing: bool,
+}
+
+impl BucketState {
+    fn refill(&mut self, now: Instant) {
+        if self.reset_at.is_some_and(|reset_at| now >= reset_at) {
+            self.remaining = self.limit;
+            self.reset_at = None;
+        }
+    }
+
+    fn update(&mut self, headers: &HeaderMap) {
+        if let Some(limit) = header(headers, "x-ratelimit-limit").and_then(|value| value.parse().ok()) {
+            self.limit = Some(limit);
+        }
+        // Requests still in flight are not counted in the header yet, so it can only lower what is left.
+        if let Some(remaining) = header(headers, "x-ratelimit-remaining").and_then(|value| value.parse::<u32>().ok()) {
+            self.remaining = Some(self.remaining.map_or(remaining, |current| current.min(remaining)));
+        }
+        if let Some(reset_after) = header(headers, "x-ratelimit-reset-after").and_then(|value| value.parse::<f64>().ok()) {
+            self.reset_at = Some(Instant::now() + Duration::from_secs_f64(reset_after.max(0.0)));
+        }
+
+        // Without a reset to wait for, the bucket falls back to probing instead of blocking forever.
+        if self.remaining == Some(0) && self.reset_at.is_none() {
+            self.remaining = None;
+        }
+    }
+
+    fn idle(&self, now: Instant) -> bool {
+        !self.probing && self.reset_at.is_none_or(|reset_at| now >= reset_at)
+    }
+}
+
+#[derive(Default)]
+pub struct Bucket {
+    state: Mutex<BucketState>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    // Maps a route to the bucket hash Discord reported for it.
+    routes: Mutex<HashMap<String, String>>,
+    buckets: Mutex<HashMap<String, Arc<Bucket>>>,
+    global_until: Mutex<Option<Instant>>,
+}
+
+pub struct RateLimit {
+    pub retry_after: Duration,
+    pub global: bool,
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// Ids after these segments are part of the rate limit key, any other id shares the bucket.
+const MAJOR_PARAMETERS: [&str; 3] = ["channels", "guilds", "webhooks"];
+
+pub fn route_key(method: &str, path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    let mut key = vec![method.to_string()];
+    let mut previous = "";
+    // Interaction ids and tokens never limit a route and must not end up in keys or recordings.
+    let mut redact: u8 = 0;
+
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let is_id = segment.chars().all(|c| c.is_ascii_digit());
+        if previous == "reactions" {
+            // Everything after the emoji is the same route regardless of which emoji or user.
+            key.push(":emoji".to_string());
+            break;
+        } else if redact > 0 {
+            key.push(if redact == 1 { ":token" } else { ":id" }.to_string());
+            redact -= 1;
+        } else if is_id && !MAJOR_PARAMETERS.contains(&previous) {
+            key.push(":id".to_string());
+        } else {
+            key.push(segment.to_string());
+        }
+
+        if segment == "interactions" {
+            redact = 2;
+        } else if previous == "webhooks" && is_id {
+            redact = 1;
+        }
+        previous = segment;
+    }
+
+    key.join("/")
+}
+
+// Interaction callbacks have a 3 second deadline and no shared limit, so they are never queued.
+fn exempt(route: &str) -> bool {
+    route.starts_with("POST/interactions/")
+}
+
+// The major parameter is kept so the same bucket hash in two channels is limited separately.
+fn major_parameter(route: &str) -> &str {
+    let segments: Vec<&str> = route.split('/').collect();
+    segments
+        .windows(2)
+        .find(|pair| MAJOR_PARAMETERS.contains(&pair[0]))
+        .map(|pair| pair[1])
+        .unwrap_or_default()
+}
+
+impl RateLimiter {
+    async fn bucket_key(&self, route: &str) -> String {
+        match self.routes.lock().await.get(route) {
+            Some(hash) => format!("{}:{}", hash, major_parameter(route)),
+            None => route.to_string(),
+        }
+    }
+
+    // Reserves one request on the route's bucket, the bucket is only locked long enough to count it.
+    pub async fn acquire(&self, route: &str) -> Option<Arc<Bucket>> {
+        if exempt(route) {
+            return None;
+        }
+
+        loop {
+            self.wait_global().await;
+
+            // Resolved on every pass, the first response may have moved the route to a shared bucket.
+            let key = self.bucket_key(route).await;
+            let bucket = {
+                let mut buckets = self.buckets.lock().await;
+                Self::evict(&mut buckets);
+                let bucket = buckets.entry(key).or_default().clone();
+                // A route whose shared bucket was evicted starts over on a bucket of its own.
+                self.routes.lock().await.retain(|route, hash| buckets.contains_key(&format!("{}:{}", hash, major_parameter(route))));
+                bucket
+            };
+
+            let changed = bucket.changed.notified();
+            let mut state = bucket.state.lock().await;
+            let now = Instant::now();
+            state.refill(now);
+
+            match (state.remaining, state.reset_at) {
+                (Some(0), Some(reset_at)) => {
+                    drop(state);
+                    time::sleep_until(reset_at).await;
+                }
+                (Some(remaining), _) if remaining > 0 => {
+                    state.remaining = Some(remaining - 1);
+                    return Some(bucket.clone());
+                }
+                // Used up before any response reported when it resets, wait for one.
+                (Some(0), None) => {
+                    drop(state);
+                    changed.await;
+                }
+                // Until Discord reports the limit, requests go one at a time.
+                _ if !state.probing => {
+                    state.probing = true;
+                    return Some(bucket.clone());
+                }
+                _ => {
+                    drop(state);
+                    changed.await;
+                }
+            }
+        }
+    }
+
+    fn evict(buckets: &mut HashMap<String, Arc<Bucket>>) {
+        let now = Instant::now();
+        buckets.retain(|_, bucket| {
+            Arc::strong_count(bucket) > 1 || bucket.state.try_lock().map_or(true, |state| !state.idle(now))
+        });
+    }
+
+    async fn wait_global(&self) {
+        let until = *self.global_until.lock().await;
+        if let Some(until) = until {
+            time::sleep_until(until).await;
+        }
+    }
+
+    pub async fn update(&self, route: &str, bucket: Option<&Arc<Bucket>>, headers: &HeaderMap) {
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return,
+        };
+
+        let state = {
+            let mut state = bucket.state.lock().await;
+            state.probing = false;
+            state.update(headers);
+            state.clone()
+        };
+
+        // The route moves to its shared bucket before anyone waiting is woken, so no request is counted twice.
+        if let Some(hash) = header(headers, "x-ratelimit-bucket") {
+            let previous = self.routes.lock().await.insert(route.to_string(), hash.to_string());
+            if previous.as_deref() != Some(hash) {
+                let key = format!("{}:{}", hash, major_parameter(route));
+                self.buckets.lock().await.entry(key).or_insert_with(|| Arc::new(Bucket { state: Mutex::new(state), changed: Notify::new() }));
+            }
+        }
+
+        bucket.changed.notify_waiters();
+    }
+
+    pub async fn limited(&self, route: &str, bucket: Option<&Arc<Bucket>>, headers: &HeaderMap, body: &str) -> RateLimit {
+        let body = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+        let retry_after = body["retry_after"].as_f64()
+            .or_else(|| header(headers, "retry-after").and_then(|value| value.parse().ok()))
+            .unwrap_or(1.0);
+        let global = body["global"].as_bool().unwrap_or(false)
+            || header(headers, "x-ratelimit-global").is_some_and(|value| value == "true");
+
+        let limit = RateLimit { retry_after: Duration::from_secs_f64(retry_after.max(0.0)), global };
+        verbose_log_async(format!("Rate limited on {} for {:?} (global: {})", route, limit.retry_after, limit.global).as_str()).await;
+
+        let until = Instant::now() + limit.retry_after;
+        if limit.global {
+            *self.global_until.lock().await = Some(until);
+        } else if let Some(bucket) = bucket {
+            let mut state = bucket.state.lock().await;
+            state.remaining = Some(0);
+            state.reset_at = Some(state.reset_at.map_or(until, |reset_at| reset_at.max(until)));
+        }
+
+        limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_key_keeps_major_parameters_only() {
+        assert_eq!(route_key("PATCH", "/channels/100/messages/200"), "PATCH/channels/100/messages/:id");
+        assert_eq!(route_key("PUT", "/channels/100/messages/200/reactions/%F0%9F%91%8D/@me"), "PUT/channels/100/messages/:id/reactions/:emoji");
+        assert_eq!(route_key("POST", "/interactions/1/token/callback"), "POST/interactions/:id/:token/callback");
+        assert_eq!(route_key("GET", "/applications/1/guilds/2/commands"), "GET/applications/:id/guilds/2/commands");
+        assert_eq!(route_key("PATCH", "/webhooks/1/token/messages/@original"), "PATCH/webhooks/1/:token/messages/@original");
+    }
+
+    #[test]
+    fn major_parameter_is_the_first_channel_or_guild() {
+        assert_eq!(major_parameter("PATCH/channels/100/messages/:id"), "100");
+        assert_eq!(major_parameter("POST/interactions/:id/:token/callback"), "");
+    }
+
+    #[tokio::test]
+    async fn idle_buckets_are_evicted() {
+        let limiter = RateLimiter::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-bucket", "messages".parse().unwrap());
+        for channel in 0..10 {
+            let route = route_key("POST", &format!("/channels/{}/messages", channel));
+            let bucket = limiter.acquire(&route).await;
+            limiter.update(&route, bucket.as_ref(), &headers).await;
+        }
+        assert!(limiter.acquire("POST/interactions/:id/:token/callback").await.is_none());
+
+        let _held = limiter.acquire("GET/channels/1/messages/:id").await;
+        assert_eq!(limiter.buckets.lock().await.len(), 1);
+        assert!(limiter.routes.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+use lazy_static::lazy_static;
+use reqwest::{RequestBuilder, Response};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::ratelimit::route_key;
+use crate::utility::{verbose_log_async, CONFIG};
+
+lazy_static! {
+    static ref RECORD_FILE: Mutex<Option<fs::File>> = Mutex::new(None);
+}
+
+pub async fn record_gateway(raw: &str) {
+    if CONFIG.record_file.is_none() {
+        return;
+    }
+
+    let mut payload = serde_json::from_str::<serde_json::Value>(raw).unwrap_or_else(|_| serde_json::Value::String(raw.to_string()));
+    if payload["d"]["token"].is_string() {
+        payload["d"]["token"] = serde_json::Value::String(":token".to_string());
+    }
+    append(serde_json::json!({
+        "kind": "gateway",
+        "time": chrono::offset::Local::now().to_rfc3339(),
+        "payload": payload,
+    })).await;
+}
+
+pub async fn send(builder: RequestBuilder) -> reqwest::Result<Response> {
+    let (client, request) = builder.build_split();
+    let request = request?;
+
+    if CONFIG.record_file.is_some() {
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|bytes| String::from_utf8_lossy(bytes).to_string());
+
+        append(serde_json::json!({
+            "kind": "rest",
+            "time": chrono::offset::Local::now().to_rfc3339(),
+            // The route key drops interaction and webhook tokens, which must never be written to disk.
+            "route": route_key(request.method().as_str(), request.url().path()),
+            "body": body,
+        })).await;
+    }
+
+    client.execute(request).await
+}
+
+async fn append(entry: serde_json::Value) {
+    let path = match CONFIG.record_file.as_ref() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let mut file = RECORD_FILE.lock().await;
+
+    if file.is_none() {
+        match fs::OpenOptions::new().append(true).create(true).open(path).await {
+            Ok(opened) => *file = Some(opened),
+            Err(e) => {
+                verbose_log_async(format!("Failed to open record file {}: {}", path, e).as_str()).await;
+                return;
+            }
+        }
+    }
+
+    if let Some(file) = file.as_mut() {
+        let line = format!("{}\n", entry);
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            verbose_log_async(format!("Failed to write record file {}: {}", path, e).as_str()).await;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use tokio::fs;
+use tokio::time::Duration;
+
+use crate::gateway::{event_handler, registry_for};
+use crate::mock::MockServer;
+use crate::model::GatewayPayload;
+use crate::shutdown;
+use crate::utility::{verbose_log_async, CONFIG};
+
+pub async fn run(path: &str) -> Result<(), ()> {
+    let server = match MockServer::start(CONFIG.replay_port).await {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Failed to start mock REST server on port {}: {}", CONFIG.replay_port, e);
+            return Err(());
+        }
+    };
+    println!("Mock REST server listening at {}", server.base_url());
+
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) => {
+            println!("Failed to read recording {}: {}", path, e);
+            return Err(());
+        }
+    };
+
+    // Channel data and votes are written relative to the working directory, so the replay gets a fresh one.
+    let data_dir = std::env::temp_dir().join(format!("shiritori-replay-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&data_dir).await;
+    if let Err(e) = fs::create_dir_all(&data_dir).await.and_then(|_| std::env::set_current_dir(&data_dir)) {
+        println!("Failed to prepare replay data directory {}: {}", data_dir.display(), e);
+        return Err(());
+    }
+    println!("Replay data directory: {}", data_dir.display());
+
+    registry_for().await;
+
+    let mut replayed = 0;
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("Skipping line {}: {}", index + 1, e);
+                continue;
+            }
+        };
+
+        if entry["kind"] != "gateway" {
+            continue;
+        }
+
+        match GatewayPayload::parse(&entry["payload"].to_string()) {
+            Ok(GatewayPayload::Dispatch { event, .. }) => {
+                event_handler(*event).await;
+                replayed += 1;
+            }
+            Ok(_) => {}
+            Err(e) => verbose_log_async(format!("Failed to parse payload on line {}: {}", index + 1, e).as_str()).await,
+        }
+    }
+
+    if !shutdown::wait_idle(Duration::from_secs(CONFIG.shutdown_timeout)).await {
+        println!("Timed out waiting for replayed tasks");
+    }
+
+    let requests = server.requests().await;
+    println!("Replayed {} events, {} REST calls were made", replayed, requests.len());
+    for request in requests {
+        println!("{} {} {}", request.method, request.path, request.body);
+    }
+
+    if let Err(e) = fs::remove_dir_all(&data_dir).await {
+        verbose_log_async(format!("Failed to remove replay data directory {}: {}", data_dir.display(), e).as_str()).await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use std::fmt;
+use lazy_static::lazy_static;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::{self, Duration};
+
+use crate::commands::{Command, ErrorResponse};
+use crate::message::{split_content, MessageBuilder, CONTENT_LIMIT};
+use crate::model::{Message, PartialChannel};
+use crate::ratelimit::{route_key, RateLimiter};
+use crate::recorder::send;
+use crate::utility::{verbose_log_async, CONFIG, DISCORD_CLIENT};
+
+lazy_static! {
+    pub static ref REST: Rest = Rest::new(DISCORD_CLIENT.clone(), CONFIG.base_api_url.clone());
+}
+
+#[derive(Debug)]
+pub enum RestError {
+    Http(reqwest::Error),
+    Api { status: u16, error: ErrorResponse },
+    Status(u16),
+    Decode(serde_json::Error),
+    RateLimited(Duration),
+}
+
+impl fmt::Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestError::Http(e) => write!(f, "HTTP error: {}", e),
+            RestError::Api { status, error } => write!(f, "Discord API error {} (HTTP {}): {} {:?}", error.code, status, error.message, error.errors),
+            RestError::Status(status) => write!(f, "Unexpected HTTP status {}", status),
+            RestError::Decode(e) => write!(f, "Failed to decode response: {}", e),
+            RestError::RateLimited(retry_after) => write!(f, "Still rate limited after {} retries, retry after {:?}", MAX_RATE_LIMIT_RETRIES, retry_after),
+        }
+    }
+}
+
+impl RestError {
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            RestError::Api { error, .. } => Some(error.code),
+            _ => None,
+        }
+    }
+}
+
+pub type RestResult<T> = Result<T, RestError>;
+
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+pub struct Rest {
+    client: Client,
+    base_url: String,
+    limiter: RateLimiter,
+}
+
+impl Rest {
+    pub fn new(client: Client, base_url: String) -> Self {
+        Self { client, base_url, limiter: RateLimiter::default() }
+    }
+
+    async fn execute<B: Serialize + ?Sized>(&self, method: Method, path: &str, body: Option<&B>) -> RestResult<String> {
+        let body = match body {
+            Some(body) => Some(serde_json::to_string(body).map_err(RestError::Decode)?),
+            None => None,
+        };
+        let route = route_key(method.as_str(), path);
+        let mut attempts = 0;
+
+        loop {
+            let bucket = self.limiter.acquire(&route).await;
+
+            let mut builder = self.client.request(method.clone(), format!("{}{}", self.base_url, path));
+            if let Some(body) = body.as_ref() {
+                builder = builder.body(body.clone());
+            }
+
+            let response = match send(builder).await {
+                Ok(response) => response,
+                Err(e) => {
+                    self.limiter.update(&route, bucket.as_ref(), &HeaderMap::new()).await;
+                    return Err(RestError::Http(e));
+                }
+            };
+            let status = response.status();
+            let headers = response.headers().clone();
+            self.limiter.update(&route, bucket.as_ref(), &headers).await;
+            let text = response.text().await.map_err(RestError::Http)?;
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let limit = self.limiter.limited(&route, bucket.as_ref(), &headers, &text).await;
+                attempts += 1;
+                if attempts > MAX_RATE_LIMIT_RETRIES {
+                    return Err(RestError::RateLimited(limit.retry_after));
+                }
+
+                // Bucketed routes wait for the reset in `acquire`, exempt ones have nothing to wait on.
+                if bucket.is_none() {
+                    time::sleep(limit.retry_after).await;
+                }
+                continue;
+            }
+
+            if status.is_success() {
+                return Ok(text);
+            }
+
+            return match serde_json::from_str::<ErrorResponse>(&text) {
+                Ok(error) => Err(RestError::Api { status: status.as_u16(), error }),
+                Err(_) => Err(RestError::Status(status.as_u16())),
+            };
+        }
+    }
+
+    async fn request<T: DeserializeOwned, B: Serialize + ?Sized>(&self, method: Method, path: &str, body: Option<&B>) -> RestResult<T> {
+        let text = self.execute(method, path, body).await?;
+        serde_json::from_str(&text).map_err(RestError::Decode)
+    }
+
+    async fn request_empty<B: Serialize + ?Sized>(&self, method: Method, path: &str, body: Option<&B>) -> RestResult<()> {
+        self.execute(method, path, body).await.map(|_| ())
+    }
+
+    pub async fn create_message<B: Serialize + ?Sized>(&self, channel_id: &str, body: &B) -> RestResult<Message> {
+        self.request(Method::POST, &format!("/channels/{}/messages", channel_id), Some(body)).await
+    }
+
+    // Content over the message limit continues in further messages, only the first one is a reply.
+    pub async fn send_content(&self, channel_id: &str, content: &str, reply_to: Option<&str>) -> RestResult<Message> {
+        let mut chunks = split_content(content, CONTENT_LIMIT).into_iter();
+        let mut first = MessageBuilder::new().content(chunks.next().unwrap_or_default());
+        if let Some(message_id) = reply_to {
+            first = first.reply_to(message_id);
+        }
+
+        let message = self.create_message(channel_id, &first).await?;
+        for chunk in chunks {
+            self.create_message(channel_id, &MessageBuilder::new().content(chunk)).await?;
+        }
+        Ok(message)
+    }
+
+    pub async fn edit_message<B: Serialize + ?Sized>(&self, channel_id: &str, message_id: &str, body: &B) -> RestResult<Message> {
+        self.request(Method::PATCH, &format!("/channels/{}/messages/{}", channel_id, message_id), Some(body)).await
+    }
+
+    pub async fn get_message(&self, channel_id: &str, message_id: &str) -> RestResult<Message> {
+        self.request(Method::GET, &format!("/channels/{}/messages/{}", channel_id, message_id), None::<&()>).await
+    }
+
+    pub async fn delete_message(&self, channel_id: &str, message_id: &str) -> RestResult<()> {
+        self.request_empty(Method::DELETE, &format!("/channels/{}/messages/{}", channel_id, message_id), None::<&()>).await
+    }
+
+    pub async fn create_reaction(&self, channel_id: &str, message_id: &str, emoji: &str) -> RestResult<()> {
+        self.request_empty(Method::PUT, &format!("/channels/{}/messages/{}/reactions/{}/@me", channel_id, message_id, emoji), None::<&()>).await
+    }
+
+    pub async fn delete_all_reactions(&self, channel_id: &str, message_id: &str) -> RestResult<()> {
+        self.request_empty(Method::DELETE, &format!("/channels/{}/messages/{}/reactions", channel_id, message_id), None::<&()>).await
+    }
+
+    pub async fn create_interaction_response<B: Serialize + ?Sized>(&self, interaction_id: &str, token: &str, body: &B) -> RestResult<()> {
+        self.request_empty(Method::POST, &format!("/interactions/{}/{}/callback", interaction_id, token), Some(body)).await
+    }
+
+    pub async fn edit_original_response<B: Serialize + ?Sized>(&self, application_id: &str, token: &str, body: &B) -> RestResult<()> {
+        self.request_empty(Method::PATCH, &format!("/webhooks/{}/{}/messages/@original", application_id, token), Some(body)).await
+    }
+
+    pub async fn create_followup_message<B: Serialize + ?Sized>(&self, application_id: &str, token: &str, body: &B) -> RestResult<()> {
+        self.request_empty(Method::POST, &format!("/webhooks/{}/{}", application_id, token), Some(body)).await
+    }
+
+    pub async fn get_channel(&self, channel_id: &str) -> RestResult<PartialChannel> {
+        self.request(Method::GET, &format!("/channels/{}", channel_id), None::<&()>).await
+    }
+
+    pub async fn modify_channel<B: Serialize + ?Sized>(&self, channel_id: &str, body: &B) -> RestResult<PartialChannel> {
+        self.request(Method::PATCH, &format!("/channels/{}", channel_id), Some(body)).await
+    }
+
+    pub async fn start_thread<B: Serialize + ?Sized>(&self, channel_id: &str, body: &B) -> RestResult<PartialChannel> {
+        self.request(Method::POST, &format!("/channels/{}/threads", channel_id), Some(body)).await
+    }
+
+    fn commands_path(application_id: &str, guild_id: Option<&str>) -> String {
+        match guild_id {
+            Some(guild_id) => format!("/applications/{}/guilds/{}/commands", application_id, guild_id),
+            None => format!("/applications/{}/commands", application_id),
+        }
+    }
+
+    pub async fn get_commands(&self, application_id: &str, guild_id: Option<&str>) -> RestResult<Vec<Command>> {
+        self.request(Method::GET, &Self::commands_path(application_id, guild_id), None::<&()>).await
+    }
+
+    pub async fn bulk_overwrite_commands(&self, application_id: &str, guild_id: Option<&str>, commands: &[Command]) -> RestResult<Vec<Command>> {
+        let path = Self::commands_path(application_id, guild_id);
+        verbose_log_async(format!("Overwriting commands at {}", path).as_str()).await;
+        self.request(Method::PUT, &path, Some(commands)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    use crate::mock::MockServer;
+
+    fn mock_rest(server: &MockServer) -> Arc<Rest> {
+        crate::utility::test_environment();
+        Arc::new(Rest::new(Client::new(), server.base_url()))
+    }
+
+    async fn statuses(server: &MockServer) -> Vec<u16> {
+        server.requests().await.iter().map(|request| request.status).collect()
+    }
+
+    #[tokio::test]
+    async fn deleted_message_is_gone() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        let message = rest.create_message("1", &serde_json::json!({ "content": "hello" })).await.unwrap();
+
+        assert!(rest.delete_message("1", &message.id).await.is_ok());
+
+        assert!(server.message(&message.id).await.is_none());
+        assert_eq!(rest.get_message("1", &message.id).await.err().and_then(|e| e.code()), Some(10008));
+    }
+
+    #[tokio::test]
+    async fn long_content_continues_in_further_messages() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        let text = vec!["word"; 1000].join("\n");
+
+        let first = rest.send_content("1", &text, Some("9")).await.unwrap();
+
+        let messages = server.messages().await;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["id"], first.id.as_str());
+        assert_eq!(messages[0]["message_reference"]["message_id"], "9");
+        assert!(messages[1]["message_reference"].is_null());
+        let sent: Vec<_> = messages.iter().map(|message| message["content"].as_str().unwrap()).collect();
+        assert_eq!(sent.join("\n"), text);
+    }
+
+    #[tokio::test]
+    async fn retries_after_rate_limit() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        server.rate_limit_next(0.05, false).await;
+        server.rate_limit_next(0.05, false).await;
+
+        let started = Instant::now();
+        let message = rest.create_message("1", &serde_json::json!({ "content": "hello" })).await;
+
+        assert!(message.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(statuses(&server).await, [429, 429, 200]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_repeated_rate_limits() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        for _ in 0..=MAX_RATE_LIMIT_RETRIES {
+            server.rate_limit_next(0.01, false).await;
+        }
+
+        let result = rest.get_message("1", "2").await;
+
+        assert!(matches!(result, Err(RestError::RateLimited(_))));
+        assert_eq!(server.requests().await.len(), MAX_RATE_LIMIT_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn global_rate_limit_holds_other_routes() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        server.rate_limit_next(0.3, true).await;
+
+        let started = Instant::now();
+        let first = tokio::spawn({
+            let rest = rest.clone();
+            async move { rest.create_message("1", &serde_json::json!({ "content": "first" })).await.is_ok() }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        let second = rest.create_message("2", &serde_json::json!({ "content": "second" })).await;
+
+        assert!(first.await.unwrap());
+        assert!(second.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(statuses(&server).await, [429, 200, 200]);
+    }
+
+    #[tokio::test]
+    async fn queues_requests_on_an_exhausted_bucket() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        server.limit_bucket(2, Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        let sends = (0..5).map(|i| {
+            let rest = rest.clone();
+            tokio::spawn(async move { rest.create_message("1", &serde_json::json!({ "content": i.to_string() })).await.is_ok() })
+        });
+        let results = futures::future::join_all(sends).await;
+
+        assert!(results.into_iter().all(|sent| sent.unwrap()));
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert!(!statuses(&server).await.contains(&429));
+    }
+
+    #[tokio::test]
+    async fn requests_on_a_known_bucket_run_in_parallel() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        server.limit_bucket(10, Duration::from_secs(5)).await;
+        assert!(rest.create_message("1", &serde_json::json!({ "content": "probe" })).await.is_ok());
+        server.delay_responses(Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        let sends = (0..4).map(|i| {
+            let rest = rest.clone();
+            tokio::spawn(async move { rest.create_message("1", &serde_json::json!({ "content": i.to_string() })).await.is_ok() })
+        });
+        let results = futures::future::join_all(sends).await;
+
+        assert!(results.into_iter().all(|sent| sent.unwrap()));
+        assert!(started.elapsed() < Duration::from_millis(600));
+    }
+
+    #[tokio::test]
+    async fn interaction_callbacks_are_not_queued() {
+        let server = MockServer::start(0).await.unwrap();
+        let rest = mock_rest(&server);
+        server.delay_responses(Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        let callbacks = (0..3).map(|i| {
+            let rest = rest.clone();
+            tokio::spawn(async move { rest.create_interaction_response(&i.to_string(), "token", &serde_json::json!({ "type": 1 })).await.is_ok() })
+        });
+        let results = futures::future::join_all(callbacks).await;
+
+        assert!(results.into_iter().all(|sent| sent.unwrap()));
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,82 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+
+lazy_static! {
+    static ref SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+    static ref IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static ref IDLE: Notify = Notify::new();
+}
+
+struct InFlightGuard;
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            IDLE.notify_waiters();
+        }
+    }
+}
+
+pub fn track<F>(task: F) -> impl Future<Output = F::Output> + Send
+where
+    F: Future + Send,
+{
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    let guard = InFlightGuard;
+
+    async move {
+        let output = task.await;
+        drop(guard);
+        output
+    }
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+pub fn begin() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                println!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => println!("Received SIGINT"),
+            _ = terminate.recv() => println!("Received SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        println!("Received Ctrl-C");
+    }
+}
+
+pub async fn wait_idle(timeout: Duration) -> bool {
+    time::timeout(timeout, async {
+        loop {
+            let idle = IDLE.notified();
+            if IN_FLIGHT.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }).await.is_ok()
+}
@@ -1,12 +1,82 @@
 use std::env;
-use reqwest::header::HeaderValue;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::game::Removal;
+
+pub const INTENT_GUILDS: u64 = 1 << 0;
+pub const INTENT_GUILD_MEMBERS: u64 = 1 << 1;
+pub const INTENT_GUILD_MESSAGES: u64 = 1 << 9;
+pub const INTENT_GUILD_MESSAGE_REACTIONS: u64 = 1 << 10;
+pub const INTENT_DIRECT_MESSAGES: u64 = 1 << 12;
+pub const INTENT_DIRECT_MESSAGE_REACTIONS: u64 = 1 << 13;
+pub const INTENT_MESSAGE_CONTENT: u64 = 1 << 15;
+
+pub const APPLICATION_FLAG_GATEWAY_MESSAGE_CONTENT: u64 = 1 << 18;
+pub const APPLICATION_FLAG_GATEWAY_MESSAGE_CONTENT_LIMITED: u64 = 1 << 19;
+
+pub struct Intents {
+    pub messages: bool,
+    pub reactions: bool,
+    pub members: bool,
+    pub direct_messages: bool,
+    pub message_content: bool,
+}
+
+impl Intents {
+    fn from_env() -> Self {
+        Self {
+            messages: env_flag("INTENT_MESSAGES", true),
+            reactions: env_flag("INTENT_REACTIONS", true),
+            members: env_flag("INTENT_MEMBERS", false),
+            direct_messages: env_flag("INTENT_DIRECT_MESSAGES", false),
+            message_content: env_flag("INTENT_MESSAGE_CONTENT", true),
+        }
+    }
+
+    pub fn bits(&self) -> u64 {
+        let mut bits = INTENT_GUILDS;
+
+        if self.messages {
+            bits |= INTENT_GUILD_MESSAGES;
+        }
+        if self.reactions {
+            bits |= INTENT_GUILD_MESSAGE_REACTIONS;
+        }
+        if self.members {
+            bits |= INTENT_GUILD_MEMBERS;
+        }
+        if self.direct_messages {
+            bits |= INTENT_DIRECT_MESSAGES;
+            if self.reactions {
+                bits |= INTENT_DIRECT_MESSAGE_REACTIONS;
+            }
+        }
+        if self.message_content {
+            bits |= INTENT_MESSAGE_CONTENT;
+        }
+
+        bits
+    }
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(val) => val == "true" || val == "1",
+        Err(_) => default,
+    }
+}
 
 pub struct BotConfig {
     pub base_api_url: String,
+    pub gateway_url: String,
+    pub dictionary_api_url: String,
+    pub weblio_url: String,
     pub token: String,
     pub app_id: String,
     pub user_agent: String,
@@ -14,12 +84,47 @@ pub struct BotConfig {
     pub auth: String,
     pub msg_dist_threshold: f64,
     pub vote_count: u8,
+    pub intents: Intents,
+    pub shutdown_timeout: u64,
+    pub http_timeout: u64,
+    pub record_file: Option<String>,
+    pub replay_file: Option<String>,
+    pub replay_port: u16,
+    pub presence_enabled: bool,
+    pub presence_interval: u64,
+    pub deleted_channel_data: Removal,
+    pub dev_guild_id: Option<String>,
 }
 
 impl BotConfig {
     pub fn new() -> Self {
-        let token = std::env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN is not set");
-        let app_id = std::env::var("DISCORD_APP_ID").expect("DISCORD_APP_ID is not set");
+        let replay_file = std::env::var("REPLAY_FILE").ok();
+        let replay_port = std::env::var("REPLAY_PORT").ok().and_then(|val| val.parse().ok()).unwrap_or(3030);
+        let (token, app_id) = match replay_file {
+            Some(_) => (
+                std::env::var("DISCORD_TOKEN").unwrap_or_else(|_| "replay".to_string()),
+                std::env::var("DISCORD_APP_ID").unwrap_or_else(|_| "0".to_string()),
+            ),
+            None => (
+                std::env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN is not set"),
+                std::env::var("DISCORD_APP_ID").expect("DISCORD_APP_ID is not set"),
+            ),
+        };
+        // Replays run fully offline, so every endpoint defaults to the local mock server.
+        let (base_api_url, dictionary_api_url, weblio_url) = match replay_file {
+            Some(_) => (
+                format!("http://127.0.0.1:{}/api/v10", replay_port),
+                format!("http://127.0.0.1:{}/dictionary/", replay_port),
+                format!("http://127.0.0.1:{}/weblio/", replay_port),
+            ),
+            None => (
+                String::from("https://discord.com/api/v10"),
+                String::from("https://api.dictionaryapi.dev/api/v2/entries/en/"),
+                String::from("https://ejje.weblio.jp/content/"),
+            ),
+        };
+        let base_api_url = std::env::var("DISCORD_API_URL").unwrap_or(base_api_url);
+        let gateway_url = std::env::var("DISCORD_GATEWAY_URL").unwrap_or_else(|_| format!("{}/gateway", base_api_url));
         let threshold = match std::env::var("MSG_DIST_THRESHOLD") {
             Ok(val) => val,
             Err(_) => {
@@ -36,7 +141,10 @@ impl BotConfig {
         };
         
         Self {
-            base_api_url: String::from("https://discord.com/api/v10"),
+            base_api_url,
+            gateway_url,
+            dictionary_api_url: std::env::var("DICTIONARY_API_URL").unwrap_or(dictionary_api_url),
+            weblio_url: std::env::var("WEBLIO_URL").unwrap_or(weblio_url),
             token: token.clone(),
             app_id: app_id.clone(),
             user_agent: String::from("DiscordBot(www.rikka-space.com, 10)"),
@@ -44,17 +152,74 @@ impl BotConfig {
             auth: format!("Bot {}", token),
             msg_dist_threshold: threshold.parse().unwrap_or(0.3),
             vote_count: vote_count.parse().unwrap_or(3),
+            intents: Intents::from_env(),
+            shutdown_timeout: std::env::var("SHUTDOWN_TIMEOUT").ok().and_then(|val| val.parse().ok()).unwrap_or(10),
+            http_timeout: std::env::var("HTTP_TIMEOUT").ok().and_then(|val| val.parse().ok()).unwrap_or(10),
+            record_file: std::env::var("RECORD_FILE").ok(),
+            replay_file,
+            replay_port,
+            presence_enabled: env_flag("PRESENCE_ENABLED", false),
+            presence_interval: std::env::var("PRESENCE_INTERVAL").ok().and_then(|val| val.parse().ok()).unwrap_or(60),
+            dev_guild_id: std::env::var("DISCORD_DEV_GUILD_ID").ok(),
+            deleted_channel_data: std::env::var("DELETED_CHANNEL_DATA").ok().and_then(|val| Removal::parse(&val)).unwrap_or(Removal::Archive),
         }
     }
 }
 
 lazy_static::lazy_static! { pub static ref CONFIG: BotConfig = BotConfig::new(); } // Globaly
 
+#[derive(Clone, Debug)]
+pub struct BotIdentity {
+    pub user_id: String,
+    pub username: String,
+    pub application_id: String,
+    pub application_flags: u64,
+}
+
+lazy_static::lazy_static! {
+    pub static ref IDENTITY: RwLock<Option<BotIdentity>> = RwLock::new(None);
+    // Set when the gateway closed with 4014, later identifies leave MESSAGE_CONTENT out.
+    static ref MESSAGE_CONTENT_REFUSED: AtomicBool = AtomicBool::new(false);
+}
+
+pub async fn set_identity(identity: BotIdentity) {
+    let mut current = IDENTITY.write().await;
+    *current = Some(identity);
+}
+
+// Returns false when there was nothing left to drop, MESSAGE_CONTENT was not asked for or already refused.
+pub fn refuse_message_content() -> bool {
+    CONFIG.intents.message_content && !MESSAGE_CONTENT_REFUSED.swap(true, Ordering::SeqCst)
+}
+
+pub fn identify_intents() -> u64 {
+    if MESSAGE_CONTENT_REFUSED.load(Ordering::SeqCst) {
+        CONFIG.intents.bits() & !INTENT_MESSAGE_CONTENT
+    } else {
+        CONFIG.intents.bits()
+    }
+}
+
+pub async fn message_content_granted() -> bool {
+    if !CONFIG.intents.message_content || MESSAGE_CONTENT_REFUSED.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let identity = IDENTITY.read().await;
+    identity.as_ref().is_none_or(|identity| {
+        identity.application_flags & (APPLICATION_FLAG_GATEWAY_MESSAGE_CONTENT | APPLICATION_FLAG_GATEWAY_MESSAGE_CONTENT_LIMITED) != 0
+    })
+}
+
+pub async fn is_bot_user(user_id: &str) -> bool {
+    let identity = IDENTITY.read().await;
+    identity.as_ref().is_some_and(|identity| identity.user_id == user_id)
+}
+
 pub async fn get_word_valid(word: String) -> bool {
-    let base_url = "https://api.dictionaryapi.dev/api/v2/entries/en/";
-    let target_url = format!("{}{}", base_url, word);
+    let target_url = format!("{}{}", CONFIG.dictionary_api_url, word);
 
-    let response = reqwest::get(&target_url).await;
+    let response = HTTP_CLIENT.get(&target_url).send().await;
 
     let response = match response {
         Ok(res) => res,
@@ -68,13 +233,18 @@ pub async fn get_word_valid(word: String) -> bool {
         Err(_) => return false,
     };
 
-    if body.chars().next() == Some('[') {
+    if body.starts_with('[') {
         return true;
     }
 
     false
 }
 
+// Lets serde leave out flags that are off.
+pub fn is_false(value: &bool) -> bool {
+    !value
+}
+
 lazy_static::lazy_static! {
     static ref VERBOSE_LOGGING_ENABLED: Mutex<Option<bool>> = Mutex::new(None);
 }
@@ -87,7 +257,7 @@ pub async fn verbose_log_async(message: &str) {
         *verbose_enabled = Some(verbose == "true" || verbose == "1");
     }
 
-    if (*verbose_enabled).unwrap_or_else(|| false) {
+    if (*verbose_enabled).unwrap_or(false) {
         let now = chrono::offset::Local::now();
         let time_string = now.format("%H:%M:%S").to_string();
         let log_message = format!("{} - {}", time_string, message);
@@ -113,14 +283,76 @@ pub async fn verbose_log_async(message: &str) {
     }
 }
 
-pub fn generate_client() -> reqwest::Client {
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert("User-Agent", HeaderValue::from_str(&CONFIG.user_agent).unwrap());
+const EXTERNAL_USER_AGENT: &str = concat!("shiritori-bot/", env!("CARGO_PKG_VERSION"));
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+lazy_static::lazy_static! {
+    // Separate pools so the bot token is only ever sent to Discord.
+    pub static ref DISCORD_CLIENT: Client = build_client(&CONFIG.user_agent, discord_headers(), Duration::from_secs(CONFIG.http_timeout));
+    pub static ref HTTP_CLIENT: Client = build_client(EXTERNAL_USER_AGENT, HeaderMap::new(), Duration::from_secs(CONFIG.http_timeout));
+}
+
+fn discord_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
     headers.insert("Authorization", HeaderValue::from_str(&CONFIG.auth).unwrap());
     headers.insert("Content-Type", HeaderValue::from_str(&CONFIG.content_type).unwrap());
-    Client::builder().default_headers(headers).build().unwrap()
+    headers
 }
 
-pub fn generate_basic_message(message: &str) -> String {
-    format!(r#"{{"content":"{}", "tts": false}}"#, message)
-}
\ No newline at end of file
+fn build_client(user_agent: &str, headers: HeaderMap, timeout: Duration) -> Client {
+    Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers)
+        .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+        .timeout(timeout)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+// Unit tests share one process, so they all read the same configuration and keep channel data in a scratch directory.
+#[cfg(test)]
+pub fn test_environment() {
+    static ENV: std::sync::Once = std::sync::Once::new();
+    ENV.call_once(|| {
+        std::env::set_var("DISCORD_TOKEN", "test");
+        std::env::set_var("DISCORD_APP_ID", "0");
+        // Nothing listens on the discard port, so requests made by handlers under test fail at once.
+        std::env::set_var("DISCORD_API_URL", "http://127.0.0.1:9/api/v10");
+        std::env::set_var("DICTIONARY_API_URL", "http://127.0.0.1:9/dictionary/");
+        std::env::set_var("WEBLIO_URL", "http://127.0.0.1:9/weblio/");
+        for name in ["REPLAY_FILE", "RECORD_FILE", "DISCORD_DEV_GUILD_ID"] {
+            std::env::remove_var(name);
+        }
+
+        let dir = std::env::temp_dir().join(format!("shiritori-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn hung_request_times_out() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accepts the connection and never answers, like a stalled lookup site.
+        let server = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = build_client(EXTERNAL_USER_AGENT, HeaderMap::new(), Duration::from_millis(200));
+        let started = std::time::Instant::now();
+        let result = client.get(format!("http://{}/", addr)).send().await;
+
+        assert!(result.is_err_and(|e| e.is_timeout()));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        server.abort();
+    }
+}
@@ -0,0 +1,290 @@
+#[allow(dead_code)]
+#[path = "../src/mock.rs"]
+mod mock;
+
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use serde_json::json;
+use tokio::time::{self, Duration, Instant};
+
+use mock::{MockServer, MOCK_APPLICATION_ID, MOCK_BOT_USER_ID};
+
+const CHANNEL_ID: &str = "100";
+const GUILD_ID: &str = "50";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Runs the real binary in its own directory so channel data never touches the repository.
+struct Bot {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Bot {
+    fn start(server: &MockServer, name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("shiritori-e2e-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_shiritori"))
+            .current_dir(&dir)
+            .env("DISCORD_TOKEN", "test")
+            .env("DISCORD_APP_ID", MOCK_APPLICATION_ID)
+            .env("DISCORD_API_URL", server.base_url())
+            .env("DICTIONARY_API_URL", server.url("/dictionary/"))
+            .env("WEBLIO_URL", server.url("/weblio/"))
+            .env("VOTE_COUNT", "2")
+            .env_remove("DISCORD_GATEWAY_URL")
+            .env_remove("DISCORD_DEV_GUILD_ID")
+            .env_remove("REPLAY_FILE")
+            .env_remove("RECORD_FILE")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Self { child, dir }
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn send_message(server: &MockServer, channel_id: &str, id: &str, content: &str, mentions: serde_json::Value) {
+    server.dispatch("MESSAGE_CREATE", json!({
+        "type": 0,
+        "id": id,
+        "channel_id": channel_id,
+        "author": { "id": "1", "username": "player" },
+        "content": content,
+        "mentions": mentions,
+    })).await;
+}
+
+// Runs /shiritori as a member with MANAGE_CHANNELS.
+async fn run_shiritori(server: &MockServer, channel_id: &str, id: &str, subcommand: serde_json::Value) {
+    server.dispatch("INTERACTION_CREATE", json!({
+        "id": id,
+        "application_id": MOCK_APPLICATION_ID,
+        "type": 2,
+        "token": "token",
+        "guild_id": GUILD_ID,
+        "channel_id": channel_id,
+        "member": { "user": { "id": "1", "username": "player" }, "permissions": "16" },
+        "data": { "name": "shiritori", "options": [subcommand] },
+    })).await;
+}
+
+async fn wait_for_message(server: &MockServer, description: &str, matches: impl Fn(&serde_json::Value) -> bool) -> serde_json::Value {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(message) = server.messages().await.into_iter().find(|message| matches(message)) {
+            return message;
+        }
+
+        assert!(Instant::now() < deadline, "timed out waiting for {}: {:?}", description, server.messages().await);
+        time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+fn field<'a>(message: &'a serde_json::Value, name: &str) -> Option<&'a str> {
+    message["embeds"][0]["fields"]
+        .as_array()?
+        .iter()
+        .find(|field| field["name"] == name)
+        .and_then(|field| field["value"].as_str())
+}
+
+fn report_finished(message: &serde_json::Value) -> bool {
+    message["embeds"][0]["fields"]
+        .as_array()
+        .is_some_and(|fields| !fields.is_empty() && fields.iter().all(|field| field["value"] != "確認中..."))
+}
+
+fn vote_reactions(message: &serde_json::Value) -> usize {
+    message["reactions"].as_array().map_or(0, |reactions| reactions.iter().filter(|reaction| reaction["me"] == true).count())
+}
+
+#[tokio::test]
+async fn registers_checks_and_votes_on_a_word() {
+    let server = MockServer::start(0).await.unwrap();
+    let _bot = Bot::start(&server, "votes");
+
+    let identify = server.wait_for_identify(TIMEOUT).await.expect("the bot never identified");
+    assert_eq!(identify["token"], "test");
+    assert!(server.commands().await.as_array().is_some_and(|commands| !commands.is_empty()));
+
+    send_message(&server, CHANNEL_ID, "10", &format!("<@{}>", MOCK_BOT_USER_ID), json!([{ "id": MOCK_BOT_USER_ID, "username": "mock" }])).await;
+    let registered = wait_for_message(&server, "the registration reply", |message| message["content"] == "チャンネルの登録が完了しました。").await;
+    assert_eq!(registered["message_reference"]["message_id"], "10");
+
+    send_message(&server, CHANNEL_ID, "11", "Apple", json!([])).await;
+    let report = wait_for_message(&server, "the word report", |message| {
+        message["embeds"][0]["title"] == "「apple」" && report_finished(message) && vote_reactions(message) == 2
+    }).await;
+    assert_eq!(field(&report, "使用済みの単語"), Some("apple と完全一致する単語は使用されていません。"));
+    assert_eq!(field(&report, "dictionary api"), Some("apple が dictionary api で見つかりました。"));
+    assert!(field(&report, "weblio").is_some_and(|value| value.contains("apple の意味")));
+    assert_eq!(report["message_reference"]["message_id"], "11");
+    assert_eq!(report["allowed_mentions"]["replied_user"], false);
+
+    let report_id = report["id"].as_str().unwrap().to_string();
+    for user_id in ["2", "3"] {
+        server.add_reaction(&report_id, "👍").await;
+        server.dispatch("MESSAGE_REACTION_ADD", json!({
+            "user_id": user_id,
+            "channel_id": CHANNEL_ID,
+            "message_id": report_id,
+            "emoji": { "id": null, "name": "👍" },
+        })).await;
+    }
+
+    let passed = wait_for_message(&server, "the vote result", |message| {
+        message["id"] == report_id.as_str() && message["content"] == "可決されました。この単語を使用リストに追加します。"
+    }).await;
+    assert_eq!(passed["reactions"], json!([]));
+
+    send_message(&server, CHANNEL_ID, "12", "apple", json!([])).await;
+    let second = wait_for_message(&server, "the second word report", |message| {
+        message["id"] != report_id.as_str() && field(message, "使用済みの単語") == Some("apple は既に使用されています。")
+    }).await;
+    assert_eq!(second["message_reference"]["message_id"], "12");
+}
+
+#[tokio::test]
+async fn runs_a_game_in_a_thread_and_archives_it() {
+    let server = MockServer::start(0).await.unwrap();
+    let bot = Bot::start(&server, "thread");
+    server.wait_for_identify(TIMEOUT).await.expect("the bot never identified");
+
+    run_shiritori(&server, CHANNEL_ID, "20", json!({ "name": "start", "type": 1, "options": [{ "name": "thread", "type": 5, "value": true }] })).await;
+    let welcome = wait_for_message(&server, "the thread welcome message", |message| {
+        message["content"] == "しりとりを開始しました。このスレッドに単語を送信してください。"
+    }).await;
+    let thread_id = welcome["channel_id"].as_str().unwrap().to_string();
+    assert_eq!(server.channel(&thread_id).await.unwrap()["parent_id"], CHANNEL_ID);
+
+    let started = server.wait_for_request(TIMEOUT, |request| request.path.contains("/interactions/20/")).await.unwrap();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&started.body).unwrap()["data"]["content"], format!("<#{}> でしりとりを開始しました。", thread_id));
+
+    let data = std::fs::read_to_string(bot.dir.join("channels").join(&thread_id).join("data.json")).unwrap();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&data).unwrap()["parent_id"], CHANNEL_ID);
+
+    send_message(&server, &thread_id, "21", "banana", json!([])).await;
+    wait_for_message(&server, "the word report in the thread", |message| {
+        message["channel_id"] == thread_id.as_str() && message["embeds"][0]["title"] == "「banana」"
+    }).await;
+
+    // The parent channel has no game of its own.
+    send_message(&server, CHANNEL_ID, "22", "cherry", json!([])).await;
+
+    run_shiritori(&server, &thread_id, "23", json!({ "name": "stop", "type": 1 })).await;
+    let archived = server
+        .wait_for_request(TIMEOUT, |request| request.method == "PATCH" && request.path.ends_with(&format!("/channels/{}", thread_id)))
+        .await;
+    assert!(archived.is_some(), "the thread was never archived");
+    assert_eq!(server.channel(&thread_id).await.unwrap()["archived"], true);
+    assert!(!server.messages().await.iter().any(|message| message["embeds"][0]["title"] == "「cherry」"));
+}
+
+#[tokio::test]
+async fn registers_a_thread_known_from_the_gateway_without_looking_it_up() {
+    let server = MockServer::start(0).await.unwrap();
+    let bot = Bot::start(&server, "known-thread");
+    server.wait_for_identify(TIMEOUT).await.expect("the bot never identified");
+
+    server.dispatch("GUILD_CREATE", json!({
+        "id": GUILD_ID,
+        "name": "guild",
+        "channels": [{ "id": CHANNEL_ID, "type": 0, "parent_id": "90" }],
+        "threads": [{ "id": "300", "type": 11, "parent_id": CHANNEL_ID }],
+    })).await;
+    send_message(&server, "300", "50", &format!("<@{}>", MOCK_BOT_USER_ID), json!([{ "id": MOCK_BOT_USER_ID, "username": "mock" }])).await;
+    send_message(&server, CHANNEL_ID, "51", &format!("<@{}>", MOCK_BOT_USER_ID), json!([{ "id": MOCK_BOT_USER_ID, "username": "mock" }])).await;
+    for channel_id in ["300", CHANNEL_ID] {
+        wait_for_message(&server, "the registration reply", |message| {
+            message["channel_id"] == channel_id && message["content"] == "チャンネルの登録が完了しました。"
+        }).await;
+    }
+
+    let data = |channel_id: &str| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(bot.dir.join("channels").join(channel_id).join("data.json")).unwrap()).unwrap()
+    };
+    assert_eq!(data("300")["parent_id"], CHANNEL_ID);
+    assert!(data(CHANNEL_ID)["parent_id"].is_null());
+    assert!(!server.requests().await.iter().any(|request| request.method == "GET" && request.path.contains("/channels/")));
+}
+
+#[tokio::test]
+async fn identifies_again_without_a_refused_message_content_intent() {
+    const MESSAGE_CONTENT: u64 = 1 << 15;
+    let server = MockServer::start(0).await.unwrap();
+    server.reject_intents(MESSAGE_CONTENT).await;
+    let _bot = Bot::start(&server, "intents");
+
+    let identify = server.wait_for_identify(TIMEOUT).await.expect("the bot never identified");
+    assert_ne!(identify["intents"].as_u64().unwrap() & MESSAGE_CONTENT, 0);
+    let retried = server
+        .wait_for_gateway(TIMEOUT, |payload| payload["op"] == 2 && payload["d"]["intents"].as_u64().is_some_and(|intents| intents & MESSAGE_CONTENT == 0))
+        .await;
+    assert!(retried.is_some(), "the bot never identified without MESSAGE_CONTENT");
+
+    send_message(&server, CHANNEL_ID, "30", "", json!([{ "id": MOCK_BOT_USER_ID, "username": "mock" }])).await;
+    wait_for_message(&server, "the registration reply", |message| message["content"] == "チャンネルの登録が完了しました。").await;
+    send_message(&server, CHANNEL_ID, "31", "", json!([])).await;
+    let warning = wait_for_message(&server, "the missing content warning", |message| {
+        message["content"] == "メッセージの内容を読み取れません。/word コマンドで単語を送信してください。"
+    }).await;
+    assert_eq!(warning["message_reference"]["message_id"], "31");
+}
+
+#[tokio::test]
+async fn resumes_the_session_when_asked_to_reconnect() {
+    let server = MockServer::start(0).await.unwrap();
+    let _bot = Bot::start(&server, "resume");
+    server.wait_for_identify(TIMEOUT).await.expect("the bot never identified");
+
+    server.request_reconnect().await;
+    let resume = server.wait_for_gateway(TIMEOUT, |payload| payload["op"] == 6).await.expect("the bot never resumed");
+    assert_eq!(resume["d"]["session_id"], "mock");
+    assert_eq!(resume["d"]["token"], "test");
+    assert_eq!(resume["d"]["seq"], 1);
+
+    send_message(&server, CHANNEL_ID, "40", &format!("<@{}>", MOCK_BOT_USER_ID), json!([{ "id": MOCK_BOT_USER_ID, "username": "mock" }])).await;
+    wait_for_message(&server, "the registration reply after resuming", |message| message["content"] == "チャンネルの登録が完了しました。").await;
+    assert_eq!(server.requests().await.iter().filter(|request| request.path.ends_with("/gateway")).count(), 1);
+    assert_eq!(server.gateway_payloads().await.iter().filter(|payload| payload["op"] == 2).count(), 1);
+}
+
+#[test]
+fn replays_a_recording_in_its_own_data_directory() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let dir = std::env::temp_dir().join(format!("shiritori-e2e-replay-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_shiritori"))
+        .current_dir(&dir)
+        .env("REPLAY_FILE", concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay.jsonl"))
+        .env("REPLAY_PORT", port.to_string())
+        .env_remove("DISCORD_API_URL")
+        .env_remove("DISCORD_GATEWAY_URL")
+        .env_remove("DICTIONARY_API_URL")
+        .env_remove("WEBLIO_URL")
+        .env_remove("RECORD_FILE")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let leftovers: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(output.status.success(), "{}", stdout);
+    let calls: Vec<&str> = stdout.lines().filter(|line| line.starts_with("POST ") || line.starts_with("PUT ") || line.starts_with("PATCH ")).collect();
+    assert!(calls.iter().any(|call| call.starts_with("POST /api/v10/channels/100/messages ") && call.contains("チャンネルの登録が完了しました。")), "{}", stdout);
+    assert!(calls.iter().any(|call| call.starts_with("POST /api/v10/channels/100/messages ") && call.contains("「apple」")), "{}", stdout);
+    assert!(calls.iter().any(|call| call.starts_with("PUT /api/v10/channels/100/messages/") && call.contains("/reactions/")), "{}", stdout);
+    assert!(leftovers.is_empty(), "the replay wrote into the working directory");
+}